@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use syscall::data::TimeSpec;
+use syscall::error::{Error, Result, EINVAL};
+use syscall::flag::CLOCK_REALTIME;
+
+/// Lines kept before the oldest ones are dropped
+const AUDIT_LINES: usize = 4096;
+
+/// Trail of pty lifecycle and privileged operations
+pub struct Audit {
+    lines: VecDeque<String>,
+    /// Sequence number of the first line in `lines`
+    first: u64,
+}
+
+impl Audit {
+    pub fn new() -> Self {
+        Audit {
+            lines: VecDeque::new(),
+            first: 0,
+        }
+    }
+
+    /// Append a line, prefixed with the realtime clock
+    pub fn log(&mut self, event: fmt::Arguments) {
+        let mut time = TimeSpec::default();
+        let _ = syscall::clock_gettime(CLOCK_REALTIME, &mut time);
+
+        self.lines
+            .push_back(format!("{}.{:09} {}\n", time.tv_sec, time.tv_nsec, event));
+
+        if self.lines.len() > AUDIT_LINES {
+            self.lines.pop_front();
+            self.first += 1;
+        }
+    }
+}
+
+/// Reads the audit trail line by line
+#[derive(Clone)]
+pub struct PtyAudit {
+    /// Sequence number of the next line to read
+    next: u64,
+}
+
+impl PtyAudit {
+    pub fn new() -> Self {
+        PtyAudit { next: 0 }
+    }
+
+    pub fn read(&mut self, audit: &Audit, buf: &mut [u8]) -> Result<usize> {
+        // Lines that were dropped in the meantime are skipped
+        self.next = self.next.max(audit.first);
+
+        let mut i = 0;
+        for line in audit.lines.iter().skip((self.next - audit.first) as usize) {
+            if i + line.len() > buf.len() {
+                if i == 0 {
+                    return Err(Error::new(EINVAL));
+                }
+                break;
+            }
+
+            buf[i..i + line.len()].copy_from_slice(line.as_bytes());
+            i += line.len();
+            self.next += 1;
+        }
+
+        Ok(i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use syscall::error::EINVAL;
+
+    use super::{Audit, PtyAudit, AUDIT_LINES};
+
+    fn read_all(reader: &mut PtyAudit, audit: &Audit) -> Vec<String> {
+        let mut buf = [0; 4096];
+        let mut lines = Vec::new();
+        loop {
+            let count = reader.read(audit, &mut buf).unwrap();
+            if count == 0 {
+                return lines;
+            }
+            let text = std::str::from_utf8(&buf[..count]).unwrap();
+            lines.extend(text.lines().map(String::from));
+        }
+    }
+
+    #[test]
+    fn reads_each_line_once() {
+        let mut audit = Audit::new();
+        let mut reader = PtyAudit::new();
+        audit.log(format_args!("one"));
+        audit.log(format_args!("two"));
+
+        let lines = read_all(&mut reader, &audit);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" one"));
+        assert!(lines[1].ends_with(" two"));
+        assert!(read_all(&mut reader, &audit).is_empty());
+
+        audit.log(format_args!("three"));
+        let lines = read_all(&mut reader, &audit);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with(" three"));
+    }
+
+    #[test]
+    fn skips_dropped_lines() {
+        let mut audit = Audit::new();
+        let mut reader = PtyAudit::new();
+        audit.log(format_args!("line 0"));
+        assert_eq!(read_all(&mut reader, &audit).len(), 1);
+
+        // Overflow the trail so lines the reader has not seen yet are dropped
+        for i in 1..AUDIT_LINES + 10 {
+            audit.log(format_args!("line {i}"));
+        }
+
+        let lines = read_all(&mut reader, &audit);
+        assert_eq!(lines.len(), AUDIT_LINES);
+        assert!(lines[0].ends_with(" line 10"));
+        assert!(lines[AUDIT_LINES - 1].ends_with(&format!(" line {}", AUDIT_LINES + 9)));
+    }
+
+    #[test]
+    fn buffer_shorter_than_a_line() {
+        let mut audit = Audit::new();
+        let mut reader = PtyAudit::new();
+        audit.log(format_args!("a line longer than the buffer"));
+
+        let mut buf = [0; 8];
+        assert_eq!(reader.read(&audit, &mut buf).err().unwrap().errno, EINVAL);
+
+        // Nothing was consumed, a big enough buffer still gets the line
+        assert_eq!(read_all(&mut reader, &audit).len(), 1);
+    }
+}
@@ -0,0 +1,42 @@
+use std::mem;
+
+use syscall::error::{Error, Result, EINVAL};
+
+use crate::pty::Pty;
+use crate::resource::{copy_value, parse_usize};
+
+/// Longest output may be held back, in microseconds
+pub const LATENCY_MAX: usize = 1_000_000;
+
+/// Read the output coalescing policy of `pty`, the latency in microseconds
+/// followed by the batch size in bytes
+pub fn read(pty: &Pty, buf: &mut [u8]) -> usize {
+    let mut policy = [0; 2 * mem::size_of::<usize>()];
+    let (latency, batch) = policy.split_at_mut(mem::size_of::<usize>());
+    latency.copy_from_slice(&pty.coalesce_latency.to_ne_bytes());
+    batch.copy_from_slice(&pty.coalesce_batch.to_ne_bytes());
+
+    copy_value(&policy, buf)
+}
+
+/// Set the output coalescing policy of `pty`, laid out as for `read`.
+/// Latencies above `LATENCY_MAX` are EINVAL
+pub fn write(pty: &mut Pty, buf: &[u8]) -> Result<usize> {
+    if buf.len() != 2 * mem::size_of::<usize>() {
+        return Err(Error::new(EINVAL));
+    }
+    let (latency, batch) = buf.split_at(mem::size_of::<usize>());
+
+    let latency = parse_usize(latency)?;
+    if latency > LATENCY_MAX {
+        return Err(Error::new(EINVAL));
+    }
+
+    pty.coalesce_latency = latency;
+    pty.coalesce_batch = parse_usize(batch)?;
+
+    // Whatever was held so far goes out under the old policy
+    pty.release_output();
+
+    Ok(buf.len())
+}
@@ -0,0 +1,32 @@
+use syscall::dirent::{DirEntry, DirentBuf, DirentKind};
+use syscall::error::Result;
+
+/// Files under the directory of a pty that any holder may open, the control
+/// only ones are reached by dup from the control term
+pub const PTY_FILES: [&str; 6] = ["pgrp", "termios", "winsize", "inject", "session", "exclusive"];
+
+/// Fill `buf` with `entries`, each with the offset to resume listing after it.
+/// Entries that do not fit are left for the next call
+pub fn list(
+    mut buf: DirentBuf<&mut [u8]>,
+    entries: impl Iterator<Item = (u64, String, DirentKind)>,
+) -> Result<DirentBuf<&mut [u8]>> {
+    for (listed, (next_opaque_id, name, kind)) in entries.enumerate() {
+        let entry = DirEntry {
+            inode: 0,
+            next_opaque_id,
+            name: &name,
+            kind,
+        };
+
+        if let Err(err) = buf.entry(entry) {
+            // Only fail when not even one entry fits
+            if listed == 0 {
+                return Err(err);
+            }
+            break;
+        }
+    }
+
+    Ok(buf)
+}
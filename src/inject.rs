@@ -0,0 +1,27 @@
+use syscall::error::{Error, Result, EAGAIN, EPERM};
+use syscall::flag::O_NONBLOCK;
+
+use crate::policy::POLICY_INJECT;
+use crate::pty::Pty;
+
+/// Push `buf` into the input stream of `pty` as if it had been typed
+pub fn write(pty: &mut Pty, flags: usize, uid: u32, buf: &[u8]) -> Result<Option<usize>> {
+    // Only the owner may inject, unless the pty allows anyone holding it to
+    if uid != 0 && uid != pty.owner_uid && pty.policy & POLICY_INJECT == 0 {
+        pty.audit("inject_denied", format_args!("uid={}", uid));
+        return Err(Error::new(EPERM));
+    }
+
+    let Some(count) = pty.input_count(buf)? else {
+        return if flags & O_NONBLOCK == O_NONBLOCK {
+            Err(Error::new(EAGAIN))
+        } else {
+            Ok(None)
+        };
+    };
+
+    pty.audit("inject", format_args!("uid={} bytes={}", uid, count));
+    pty.input(&buf[..count]);
+
+    Ok(Some(count))
+}
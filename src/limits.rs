@@ -0,0 +1,253 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use syscall::error::{Error, Result, EAGAIN, ENOSPC};
+
+use crate::pty::PIPE_BUF;
+
+/// Caps on what clients may allocate, set on the command line
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Ptys a single uid may own at once
+    pub ptys_per_uid: usize,
+    /// Ptys in total
+    pub ptys: usize,
+    /// Pty numbers are taken from below this
+    pub pty_number: usize,
+    /// Bytes queued in each direction of one pty
+    pub pty_bytes: usize,
+    /// Bytes queued across all ptys
+    pub bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            ptys_per_uid: 256,
+            ptys: 1024,
+            pty_number: 1024,
+            pty_bytes: 64 * 1024,
+            bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    /// Parse `--max-ptys-per-uid=N`, `--max-ptys=N`, `--max-pty-number=N`,
+    /// `--max-pty-bytes=N` and `--max-bytes=N`, keeping the default for anything
+    /// missing. Queues smaller than `PIPE_BUF` are refused
+    pub fn from_args(args: impl Iterator<Item = String>) -> Self {
+        let mut limits = Limits::default();
+
+        for arg in args {
+            let Some((name, value)) = arg.split_once('=') else {
+                eprintln!("ptyd: ignoring argument {arg}");
+                continue;
+            };
+            let Ok(value) = value.parse::<usize>() else {
+                eprintln!("ptyd: invalid value for {name}: {value}");
+                continue;
+            };
+
+            match name {
+                "--max-ptys-per-uid" => limits.ptys_per_uid = value,
+                "--max-ptys" => limits.ptys = value,
+                "--max-pty-number" => limits.pty_number = value,
+                "--max-pty-bytes" if value < PIPE_BUF => {
+                    eprintln!("ptyd: {name} has to be at least {PIPE_BUF}");
+                }
+                "--max-pty-bytes" => limits.pty_bytes = value,
+                "--max-bytes" => limits.bytes = value,
+                _ => eprintln!("ptyd: ignoring argument {arg}"),
+            }
+        }
+
+        limits
+    }
+}
+
+/// Daemon wide resource usage, shared by every pty
+pub struct Usage {
+    pub limits: Limits,
+    pub ptys: usize,
+    pub ptys_per_uid: BTreeMap<u32, usize>,
+    pub bytes: usize,
+}
+
+impl Usage {
+    pub fn new(limits: Limits) -> Self {
+        Usage {
+            limits,
+            ptys: 0,
+            ptys_per_uid: BTreeMap::new(),
+            bytes: 0,
+        }
+    }
+}
+
+/// Share of the daemon's resources held by a single pty, given back on drop
+pub struct Quota {
+    usage: Rc<RefCell<Usage>>,
+    uid: u32,
+    bytes: usize,
+}
+
+impl Quota {
+    /// Account for a new pty created by `uid`
+    pub fn new(usage: Rc<RefCell<Usage>>, uid: u32) -> Result<Self> {
+        {
+            let mut usage = usage.borrow_mut();
+            let per_uid = usage.ptys_per_uid.get(&uid).copied().unwrap_or(0);
+            if per_uid >= usage.limits.ptys_per_uid || usage.ptys >= usage.limits.ptys {
+                return Err(Error::new(EAGAIN));
+            }
+
+            usage.ptys_per_uid.insert(uid, per_uid + 1);
+            usage.ptys += 1;
+        }
+
+        Ok(Quota {
+            usage,
+            uid,
+            bytes: 0,
+        })
+    }
+
+    /// Capacity of each queue of the pty
+    pub fn pty_bytes(&self) -> usize {
+        self.usage.borrow().limits.pty_bytes
+    }
+
+    /// Bytes that may still be queued across ptyd, ENOSPC once none are left
+    pub fn room(&self) -> Result<usize> {
+        let usage = self.usage.borrow();
+
+        match usage.limits.bytes.saturating_sub(usage.bytes) {
+            0 => Err(Error::new(ENOSPC)),
+            room => Ok(room),
+        }
+    }
+
+    /// Record that the pty now has `bytes` queued
+    pub fn set_bytes(&mut self, bytes: usize) {
+        let mut usage = self.usage.borrow_mut();
+        usage.bytes = usage.bytes - self.bytes + bytes;
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for Quota {
+    fn drop(&mut self) {
+        self.set_bytes(0);
+
+        let mut usage = self.usage.borrow_mut();
+        usage.ptys -= 1;
+        if let Some(per_uid) = usage.ptys_per_uid.get_mut(&self.uid) {
+            *per_uid -= 1;
+            if *per_uid == 0 {
+                usage.ptys_per_uid.remove(&self.uid);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use syscall::error::{EAGAIN, ENOSPC};
+
+    use super::{Limits, Quota, Usage};
+
+    fn args(args: &[&str]) -> Limits {
+        Limits::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn usage(limits: Limits) -> Rc<RefCell<Usage>> {
+        Rc::new(RefCell::new(Usage::new(limits)))
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let limits = args(&[
+            "--max-ptys-per-uid=2",
+            "--max-ptys=3",
+            "--max-pty-number=4",
+            "--max-pty-bytes=8192",
+            "--max-bytes=5",
+        ]);
+
+        assert_eq!(limits.ptys_per_uid, 2);
+        assert_eq!(limits.ptys, 3);
+        assert_eq!(limits.pty_number, 4);
+        assert_eq!(limits.pty_bytes, 8192);
+        assert_eq!(limits.bytes, 5);
+    }
+
+    #[test]
+    fn keeps_defaults_for_bad_arguments() {
+        let default = Limits::default();
+        let limits = args(&["--max-ptys=many", "--max-pty-bytes=0", "--unknown=1", "stray"]);
+
+        assert_eq!(limits.ptys, default.ptys);
+        assert_eq!(limits.pty_bytes, default.pty_bytes);
+    }
+
+    #[test]
+    fn limits_ptys_per_uid() {
+        let usage = usage(Limits {
+            ptys_per_uid: 1,
+            ..Limits::default()
+        });
+
+        let first = Quota::new(usage.clone(), 1000).unwrap();
+        assert_eq!(Quota::new(usage.clone(), 1000).err().unwrap().errno, EAGAIN);
+        // Other users have their own share
+        let _other = Quota::new(usage.clone(), 1001).unwrap();
+
+        drop(first);
+        assert!(Quota::new(usage.clone(), 1000).is_ok());
+    }
+
+    #[test]
+    fn limits_ptys_in_total() {
+        let usage = usage(Limits {
+            ptys: 2,
+            ..Limits::default()
+        });
+
+        let _a = Quota::new(usage.clone(), 1000).unwrap();
+        let b = Quota::new(usage.clone(), 1001).unwrap();
+        assert_eq!(Quota::new(usage.clone(), 1002).err().unwrap().errno, EAGAIN);
+
+        drop(b);
+        assert_eq!(usage.borrow().ptys, 1);
+        assert!(Quota::new(usage.clone(), 1002).is_ok());
+    }
+
+    #[test]
+    fn releases_bytes_on_drop() {
+        let usage = usage(Limits {
+            bytes: 100,
+            ..Limits::default()
+        });
+
+        let mut a = Quota::new(usage.clone(), 1000).unwrap();
+        let mut b = Quota::new(usage.clone(), 1000).unwrap();
+        a.set_bytes(60);
+        b.set_bytes(40);
+        assert_eq!(a.room().err().unwrap().errno, ENOSPC);
+
+        b.set_bytes(10);
+        assert_eq!(a.room().unwrap(), 30);
+
+        drop(a);
+        drop(b);
+        let usage = usage.borrow();
+        assert_eq!(usage.bytes, 0);
+        assert_eq!(usage.ptys, 0);
+        assert!(usage.ptys_per_uid.is_empty());
+    }
+}
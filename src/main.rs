@@ -1,24 +1,42 @@
+use std::collections::BTreeMap;
+use std::mem;
+
 use event::{user_data, EventFlags, EventQueue};
 use libredox::errno::{EAGAIN, EBADF, EWOULDBLOCK};
 use libredox::error::Error;
 use libredox::{flag, Fd};
 
-use redox_scheme::{CallRequest, RequestKind, Response, SignalBehavior, Socket};
+use redox_scheme::{CallRequest, Id, Request, RequestKind, Response, SignalBehavior, Socket};
 use syscall::EINTR;
 use syscall::data::TimeSpec;
 
+mod audit;
+mod coalesce;
 mod controlterm;
+mod dir;
+mod inject;
+mod limits;
 mod pgrp;
+mod policy;
 mod pty;
 mod resource;
+mod ring;
+mod sak;
 mod scheme;
+mod session;
+mod slab;
 mod subterm;
 mod termios;
+mod wait;
 mod winsize;
 
+use limits::Limits;
 use scheme::PtyScheme;
+use wait::WaitKey;
 
 fn main() {
+    let limits = Limits::from_args(std::env::args().skip(1));
+
     redox_daemon::Daemon::new(move |daemon| {
         user_data! {
             enum EventSource {
@@ -30,7 +48,7 @@ fn main() {
         let event_queue = EventQueue::<EventSource>::new().expect("pty: failed to open event:");
 
         let time_path = format!("/scheme/time/{}", flag::CLOCK_MONOTONIC);
-        let mut time_file =
+        let time_file =
             Fd::open(&time_path, flag::O_NONBLOCK, 0).expect("pty: failed to open time:");
 
         let socket =
@@ -48,15 +66,21 @@ fn main() {
         println!("ptyd daemon ready");
         daemon.ready().expect("pty: failed to notify parent");
 
+        let mut timer = Timer::new(time_file).expect("pty: failed to read time");
+
         //TODO: do not set timeout if not necessary
-        timeout(&mut time_file).expect("pty: failed to set timeout");
+        timer.arm(timer.tick).expect("pty: failed to set timeout");
 
-        let mut scheme = PtyScheme::new();
-        let mut todo = Vec::new();
+        let mut scheme = PtyScheme::new(limits);
+        let mut waiting = Waiting::default();
         let mut timeout_count = 0u64;
 
-        scan_requests(&socket, &mut scheme, &mut todo).expect("pty: could not scan requests");
-        do_todos(&socket, &mut scheme, &mut todo);
+        let mut responses = Vec::new();
+
+        scan_requests(&socket, &mut scheme, &mut waiting, &mut responses)
+            .expect("pty: could not scan requests");
+        do_todos(&mut scheme, &mut waiting, &mut responses);
+        write_responses(&socket, &mut responses);
         issue_events(&socket, &mut scheme);
 
         for event_res in event_queue {
@@ -64,23 +88,32 @@ fn main() {
 
             match event.user_data {
                 EventSource::Socket => {
-                    if scan_requests(&socket, &mut scheme, &mut todo).is_err() {
+                    if scan_requests(&socket, &mut scheme, &mut waiting, &mut responses).is_err() {
                         break;
                     }
                 }
                 EventSource::Time => {
-                    timeout(&mut time_file).expect("pty: failed to set timeout");
+                    let now = timer.fired().expect("pty: failed to read time");
 
-                    timeout_count = timeout_count.wrapping_add(1);
+                    if now >= timer.tick {
+                        timer.tick = now + TICK;
+                        timeout_count = timeout_count.wrapping_add(1);
 
-                    for (_id, handle) in scheme.handles.iter_mut() {
-                        handle.timeout(timeout_count);
+                        scheme.timeout(timeout_count);
                     }
+
+                    scheme.expire(now);
                 }
             }
 
-            do_todos(&socket, &mut scheme, &mut todo);
+            do_todos(&mut scheme, &mut waiting, &mut responses);
+            write_responses(&socket, &mut responses);
             issue_events(&socket, &mut scheme);
+
+            let deadline = scheme.wakeups.borrow().next_deadline();
+            timer
+                .arm(deadline.map_or(timer.tick, |deadline| deadline.min(timer.tick)))
+                .expect("pty: failed to set timeout");
         }
 
         std::process::exit(0);
@@ -88,84 +121,194 @@ fn main() {
     .expect("pty: failed to daemonize");
 }
 
+/// Requests read from the scheme socket at once
+const REQUEST_BATCH: usize = 64;
+
 struct Todo {
     request: CallRequest,
-    cancelling: bool,
+}
+
+/// Blocked requests, parked on the wait queue they were blocked on
+#[derive(Default)]
+struct Waiting {
+    queues: BTreeMap<WaitKey, Vec<Todo>>,
+    /// Blocked on something without a wait queue, retried after every event
+    other: Vec<Todo>,
+}
+
+impl Waiting {
+    fn park(&mut self, key: Option<WaitKey>, todo: Todo) {
+        match key {
+            Some(key) => self.queues.entry(key).or_default().push(todo),
+            None => self.other.push(todo),
+        }
+    }
+
+    /// Remove the request with id `id`, wherever it is parked
+    fn remove(&mut self, id: Id) -> Option<Todo> {
+        let is_match = |t: &Todo| t.request.request().request_id() == id;
+
+        if let Some(idx) = self.other.iter().position(is_match) {
+            return Some(self.other.remove(idx));
+        }
+
+        for (key, queue) in self.queues.iter_mut() {
+            if let Some(idx) = queue.iter().position(is_match) {
+                let todo = queue.remove(idx);
+                if queue.is_empty() {
+                    let key = *key;
+                    self.queues.remove(&key);
+                }
+                return Some(todo);
+            }
+        }
+
+        None
+    }
+}
+
+/// Run a request, parking it if it blocks
+fn handle(scheme: &mut PtyScheme, waiting: &mut Waiting, responses: &mut Vec<Response>, todo: Todo) {
+    scheme.blocked = None;
+
+    if let Some(response) = todo.request.handle_scheme_block(scheme) {
+        responses.push(response);
+    } else {
+        waiting.park(scheme.blocked.take(), todo);
+    }
 }
 
 fn scan_requests(
     socket: &Socket,
     scheme: &mut PtyScheme,
-    todo: &mut Vec<Todo>,
+    waiting: &mut Waiting,
+    responses: &mut Vec<Response>,
 ) -> libredox::error::Result<()> {
+    let mut requests = [Request::default(); REQUEST_BATCH];
+
     loop {
-        let request = match socket.next_request(SignalBehavior::Restart) {
-            Ok(Some(req)) => req,
-            Ok(None) => return Err(Error::new(EBADF)),
+        let count = match socket.read_requests(&mut requests, SignalBehavior::Restart) {
+            Ok(0) => return Err(Error::new(EBADF)),
+            Ok(count) => count,
             Err(error) if error.errno == EWOULDBLOCK || error.errno == EAGAIN => break,
             Err(other) => panic!("pty: failed to read from socket: {other}"),
         };
 
-        match request.kind() {
-            RequestKind::Cancellation(req) => {
-                if let Some(idx) = todo.iter().position(|t| t.request.request().request_id() == req.id) {
-                    todo[idx].cancelling = true;
+        for request in requests[..count].iter() {
+            match request.kind() {
+                RequestKind::Cancellation(req) => {
+                    if let Some(todo) = waiting.remove(req.id) {
+                        // Give it one last try before interrupting it
+                        let response = todo.request.handle_scheme_block(scheme).unwrap_or_else(|| {
+                            Response::new(&todo.request, Err(Error::new(EINTR).into()))
+                        });
+                        responses.push(response);
+                    }
                 }
-            }
-            RequestKind::Call(request) => {
-                if let Some(response) = request.handle_scheme_block(scheme) {
-                    let _ = socket
-                        .write_response(response, SignalBehavior::Restart)
-                        .expect("pty: failed to write responses to pty scheme");
-                } else {
-                    todo.push(Todo { request, cancelling: false });
+                RequestKind::Call(request) => {
+                    handle(scheme, waiting, responses, Todo { request });
                 }
+                _ => (),
             }
-            _ => (),
         }
     }
     Ok(())
 }
 
-fn do_todos(socket: &Socket, scheme: &mut PtyScheme, todo: &mut Vec<Todo>) {
-    let mut i = 0;
-    while i < todo.len() {
-        if let Some(response) = todo[i].request.handle_scheme_block(scheme) {
-            todo.remove(i);
-            socket
-                .write_response(response, SignalBehavior::Restart)
-                .expect("pty: failed to write responses to pty scheme");
-        } else if todo[i].cancelling {
-            socket.write_response(Response::new(&todo[i].request, Err(Error::new(EINTR).into())), SignalBehavior::Restart)
-                .expect("pty: failed to write responses to pty scheme");
-            todo.remove(i);
-        } else {
-            i += 1;
+/// Retry the requests on every woken wait queue, until no more are woken
+fn do_todos(scheme: &mut PtyScheme, waiting: &mut Waiting, responses: &mut Vec<Response>) {
+    for todo in mem::take(&mut waiting.other) {
+        handle(scheme, waiting, responses, todo);
+    }
+
+    loop {
+        let woken = scheme.wakeups.borrow_mut().take();
+        if woken.is_empty() {
+            break;
+        }
+
+        for key in woken {
+            for todo in waiting.queues.remove(&key).unwrap_or_default() {
+                handle(scheme, waiting, responses, todo);
+            }
         }
     }
 }
 
+/// Send every response collected during this wakeup in one go
+fn write_responses(socket: &Socket, responses: &mut Vec<Response>) {
+    let mut i = 0;
+    while i < responses.len() {
+        let count = socket
+            .write_responses(&responses[i..], SignalBehavior::Restart)
+            .expect("pty: failed to write responses to pty scheme");
+        assert!(count > 0, "pty: scheme socket accepted no responses");
+        i += count;
+    }
+
+    responses.clear();
+}
+
+/// Post new events of the handles marked dirty since the last call
 fn issue_events(socket: &Socket, scheme: &mut PtyScheme) {
-    for (id, handle) in scheme.handles.iter_mut() {
-        let events = handle.events();
+    for id in scheme.dirty_handles() {
+        let events = scheme.events(id);
         if events != syscall::EventFlags::empty() {
             socket
-                .post_fevent(*id, events.bits())
+                .post_fevent(id, events.bits())
                 .expect("pty: failed to send scheme event");
         }
     }
 }
 
-fn timeout(time_file: &mut Fd) -> libredox::error::Result<()> {
-    let mut time = TimeSpec::default();
-    time_file.read(&mut time)?;
+/// Interval of termios timeouts, VTIME counts in these
+const TICK: u64 = 100_000_000;
 
-    time.tv_nsec += 100_000_000;
-    while time.tv_nsec >= 1_000_000_000 {
-        time.tv_sec += 1;
-        time.tv_nsec -= 1_000_000_000;
+/// Monotonic timer behind termios timeouts and output coalescing
+struct Timer {
+    file: Fd,
+    /// Time of the next termios timeout tick
+    tick: u64,
+    /// Time the timer was last set to, 0 once it fired
+    armed: u64,
+}
+
+impl Timer {
+    fn new(file: Fd) -> libredox::error::Result<Self> {
+        let mut timer = Timer {
+            file,
+            tick: 0,
+            armed: 0,
+        };
+        timer.tick = timer.now()? + TICK;
+        Ok(timer)
     }
 
-    time_file.write(&time)?;
-    Ok(())
+    fn now(&mut self) -> libredox::error::Result<u64> {
+        let mut time = TimeSpec::default();
+        self.file.read(&mut time)?;
+        Ok(wait::nanoseconds(&time))
+    }
+
+    /// Note that the timer fired, returning the current time
+    fn fired(&mut self) -> libredox::error::Result<u64> {
+        self.armed = 0;
+        self.now()
+    }
+
+    /// Make sure the timer fires no later than `at`
+    fn arm(&mut self, at: u64) -> libredox::error::Result<()> {
+        if self.armed != 0 && self.armed <= at {
+            return Ok(());
+        }
+
+        let time = TimeSpec {
+            tv_sec: (at / 1_000_000_000) as i64,
+            tv_nsec: (at % 1_000_000_000) as i32,
+        };
+        self.file.write(&time)?;
+
+        self.armed = at;
+        Ok(())
+    }
 }
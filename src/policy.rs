@@ -0,0 +1,4 @@
+/// Allow any holder of the pty to inject input, not only its owner
+pub const POLICY_INJECT: usize = 1;
+/// Let echo of typed input overtake program output that is still queued
+pub const POLICY_ECHO_FIRST: usize = 2;
@@ -1,38 +1,127 @@
-use std::collections::VecDeque;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::rc::Rc;
 
 use redox_termios::*;
 use syscall;
 use syscall::error::Result;
+use syscall::flag::{O_RDONLY, O_WRONLY};
+
+use crate::audit::Audit;
+use crate::limits::Quota;
+use crate::policy::POLICY_ECHO_FIRST;
+use crate::ring::Ring;
+use crate::wait::{self, Direction, Wakeups};
+
+/// Writes up to this size are never split
+pub const PIPE_BUF: usize = 4096;
+
+/// Mark in `miso` left by a subterm fsync, read as a packet with header 1
+pub const MISO_SYNC: u8 = 1;
+/// Mark in `mosi` where a subterm read ends, like a canonical line or EOF
+pub const MOSI_BOUNDARY: u8 = 0;
+/// Room a line ending takes in `mosi` besides the line, the byte and its boundary
+const LINE_END: usize = 2;
 
 pub struct Pty {
-    pub id: usize,
+    /// Number in `/scheme/pty/N`, the lowest free one when the pty was created
+    pub number: usize,
+    /// Key in the pty table
+    pub key: usize,
+    /// Every handle open on the pty
+    pub handles: BTreeSet<usize>,
+    /// Control terms open on the pty, it goes away with the last one
+    pub controls: usize,
+    pub owner_uid: u32,
+    pub owner_gid: u32,
+    pub mode: u16,
+    pub locked: bool,
+    pub exclusive: bool,
+    pub policy: usize,
+    /// Microseconds program output may be held back, 0 to never hold it
+    pub coalesce_latency: usize,
+    /// Bytes of held output that release it early
+    pub coalesce_batch: usize,
+    /// When held output is released, None if nothing is held
+    coalesce_deadline: Option<u64>,
+    pub sid: usize,
     pub pgrp: usize,
+    pub pgrps: BTreeSet<usize>,
     pub termios: Termios,
     pub winsize: Winsize,
     pub cooked: Vec<u8>,
-    pub miso: VecDeque<Vec<u8>>,
-    pub mosi: VecDeque<Vec<u8>>,
+    pub miso: Ring,
+    /// Echo of typed input, read before `miso` under `POLICY_ECHO_FIRST`.
+    /// Both share the room of the output direction, see `output_space`
+    pub echo: Ring,
+    pub mosi: Ring,
     pub timeout_count: u64,
     pub timeout_character: Option<u64>,
+    pub subterms: usize,
+    pub subterm_hangup: bool,
+    pub revoke_pending: bool,
+    pub sak: Vec<u8>,
+    pub sak_matched: usize,
+    pub sak_locked: bool,
+    pub quota: Quota,
+    audit: Rc<RefCell<Audit>>,
+    wakeups: Rc<RefCell<Wakeups>>,
 }
 
 impl Pty {
-    pub fn new(id: usize) -> Self {
+    pub fn new(
+        number: usize,
+        key: usize,
+        owner_uid: u32,
+        owner_gid: u32,
+        quota: Quota,
+        audit: Rc<RefCell<Audit>>,
+        wakeups: Rc<RefCell<Wakeups>>,
+    ) -> Self {
+        audit
+            .borrow_mut()
+            .log(format_args!("create pty={number} uid={owner_uid} gid={owner_gid}"));
+
         Pty {
-            id: id,
+            number,
+            key,
+            handles: BTreeSet::new(),
+            controls: 0,
+            owner_uid,
+            owner_gid,
+            mode: 0o600,
+            locked: true,
+            exclusive: false,
+            policy: 0,
+            coalesce_latency: 0,
+            coalesce_batch: 0,
+            coalesce_deadline: None,
+            sid: 0,
             pgrp: 0,
+            pgrps: BTreeSet::new(),
             termios: Termios::default(),
             winsize: Winsize::default(),
             cooked: Vec::new(),
-            miso: VecDeque::new(),
-            mosi: VecDeque::new(),
+            miso: Ring::new(quota.pty_bytes()),
+            echo: Ring::new(quota.pty_bytes()),
+            mosi: Ring::new(quota.pty_bytes()),
             timeout_count: 0,
             timeout_character: None,
+            subterms: 0,
+            subterm_hangup: false,
+            revoke_pending: false,
+            sak: Vec::new(),
+            sak_matched: 0,
+            sak_locked: false,
+            quota,
+            audit,
+            wakeups,
         }
     }
 
     pub fn path(&self, buf: &mut [u8]) -> Result<usize> {
-        let path_str = format!("/scheme/pty/{}", self.id);
+        let path_str = format!("/scheme/pty/{}", self.number);
         let path = path_str.as_bytes();
 
         let mut i = 0;
@@ -44,7 +133,182 @@ impl Pty {
         Ok(i)
     }
 
+    /// Add a line about this pty to the audit trail
+    pub fn audit(&self, event: &str, details: fmt::Arguments) {
+        self.audit
+            .borrow_mut()
+            .log(format_args!("{} pty={} {}", event, self.number, details));
+    }
+
+    /// Retry requests blocked on `direction` of this pty
+    pub fn wake(&self, direction: Direction) {
+        self.wakeups.borrow_mut().wake((self.key, direction));
+    }
+
+    /// Bytes held in the line buffer and all queues
+    pub fn queued(&self) -> usize {
+        self.cooked.len() + self.miso.len() + self.echo.len() + self.mosi.len()
+    }
+
+    /// True if a control write would not have to wait
+    pub fn input_writable(&self) -> bool {
+        self.mosi.is_empty() || self.mosi.space() >= self.cooked.len() + LINE_END
+    }
+
+    /// True if a subterm write would not have to wait
+    pub fn output_writable(&self) -> bool {
+        self.output_space() > 0
+    }
+
+    /// Room left in the output direction, `miso` and `echo` together
+    pub fn output_space(&self) -> usize {
+        self.quota
+            .pty_bytes()
+            .saturating_sub(self.miso.len() + self.echo.len())
+    }
+
+    /// How many bytes of `buf` to take now, None if the writer has to wait for
+    /// the subterm side to read
+    pub fn input_count(&self, buf: &[u8]) -> Result<Option<usize>> {
+        let room = self.quota.room()?;
+
+        let count = if self.termios.c_lflag & ICANON == ICANON {
+            self.input_count_canonical(buf, room)
+        } else {
+            // Flushing the line buffer may add a boundary besides the bytes
+            let space = self
+                .mosi
+                .space()
+                .saturating_sub(self.cooked.len() + 1)
+                .min(room);
+            buf.len().min(space)
+        };
+
+        // Writes up to PIPE_BUF wait until they fit whole, unless nothing is
+        // queued for the subterm side to read and so nothing would ever drain
+        if count == buf.len() || (count > 0 && (buf.len() > PIPE_BUF || self.mosi.is_empty())) {
+            Ok(Some(count))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Bytes of `buf` that canonical input can take, following `input` byte by
+    /// byte. Characters past the end of a full line are dropped like n_tty does,
+    /// while line endings and edits always get through
+    fn input_count_canonical(&self, buf: &[u8], room: usize) -> usize {
+        let cc = self.termios.c_cc;
+        let ends_line = |b: u8| {
+            b == b'\n' || b == b'\r' || (b != 0 && [cc[VEOF], cc[VEOL], cc[VEOL2]].contains(&b))
+        };
+
+        let mut space = self.mosi.space();
+        let mut empty = self.mosi.is_empty();
+        let mut line = self.cooked.len();
+        // Bytes added to the queues, held to the quota
+        let mut grown = 0;
+
+        let mut count = 0;
+        for &b in buf {
+            if ends_line(b) {
+                // A line that can never be queued whole is cut short instead
+                if line + LINE_END > space && !empty {
+                    break;
+                }
+                space = space.saturating_sub(line + LINE_END);
+                empty = false;
+                line = 0;
+                grown += LINE_END;
+            } else if line + LINE_END < space {
+                line += 1;
+                grown += 1;
+            }
+
+            if grown > room {
+                break;
+            }
+            count += 1;
+        }
+
+        count
+    }
+
+    /// How many bytes of `buf` to take now, None if the writer has to wait for
+    /// the control side to read
+    pub fn output_count(&self, buf: &[u8]) -> Result<Option<usize>> {
+        let space = self.output_space().min(self.quota.room()?);
+
+        // Small writes wait to fit whole if reading will make room
+        if buf.len() <= PIPE_BUF && !(self.miso.is_empty() && self.echo.is_empty()) {
+            return Ok((self.output_len(buf) <= space).then_some(buf.len()));
+        }
+
+        if !self.translates_output() {
+            let count = buf.len().min(space);
+            return Ok((count > 0).then_some(count));
+        }
+
+        // Longest prefix that fits once translated
+        let mut count = 0;
+        let mut len = 0;
+        for b in buf.iter() {
+            len += self.output_len(&[*b]);
+            if len > space {
+                break;
+            }
+            count += 1;
+        }
+
+        Ok((count > 0).then_some(count))
+    }
+
+    /// Update the daemon wide byte count after the queues changed
+    pub fn account(&mut self) {
+        let queued = self.queued();
+        self.quota.set_bytes(queued);
+    }
+
+    /// Check the access mode in `flags` against the owner and mode of the pty
+    pub fn permitted(&self, uid: u32, gid: u32, flags: usize) -> bool {
+        if uid == 0 {
+            return true;
+        }
+
+        let perm = if uid == self.owner_uid {
+            self.mode >> 6
+        } else if gid == self.owner_gid {
+            self.mode >> 3
+        } else {
+            self.mode
+        };
+
+        let mut wanted = 0;
+        if flags & O_RDONLY == O_RDONLY {
+            wanted |= 0o4;
+        }
+        if flags & O_WRONLY == O_WRONLY {
+            wanted |= 0o2;
+        }
+
+        perm & wanted == wanted
+    }
+
+    /// True if input reaches the subterm untouched, so it can be moved in bulk
+    fn transparent_input(&self) -> bool {
+        let ifl = self.termios.c_iflag;
+        let lfl = self.termios.c_lflag;
+
+        ifl & (INLCR | IGNCR | ICRNL | IXON) == 0
+            && lfl & (ECHO | ECHONL | ICANON | ISIG | IEXTEN) == 0
+            && self.sak.is_empty()
+    }
+
     pub fn input(&mut self, buf: &[u8]) {
+        if self.transparent_input() {
+            self.input_raw(buf);
+            return;
+        }
+
         let ifl = self.termios.c_iflag;
         //let ofl = &self.termios.c_oflag;
         //let cfl = &self.termios.c_cflag;
@@ -63,9 +327,15 @@ impl Pty {
         let icanon = lfl & ICANON == ICANON;
         let isig = lfl & ISIG == ISIG;
         let iexten = lfl & IEXTEN == IEXTEN;
-        let ixon = lfl & IXON == IXON;
+        let ixon = ifl & IXON == IXON;
 
         for &byte in buf.iter() {
+            if self.sak_input(byte) {
+                // Nothing typed after the key may reach the new session
+                self.secure_attention();
+                return;
+            }
+
             let mut b = byte;
 
             // Input tranlation
@@ -85,43 +355,39 @@ impl Pty {
             if icanon {
                 if b == b'\n' {
                     if echo || echonl {
-                        self.output(&[b]);
+                        self.echo(&[b]);
                     }
 
                     self.cooked.push(b);
-                    self.mosi.push_back(self.cooked.clone());
-                    self.cooked.clear();
+                    self.flush_cooked(true);
 
                     b = 0;
                 }
 
                 if is_cc(b, VEOF) {
-                    self.mosi.push_back(self.cooked.clone());
-                    self.cooked.clear();
+                    self.flush_cooked(true);
 
                     b = 0;
                 }
 
                 if is_cc(b, VEOL) {
                     if echo {
-                        self.output(&[b]);
+                        self.echo(&[b]);
                     }
 
                     self.cooked.push(b);
-                    self.mosi.push_back(self.cooked.clone());
-                    self.cooked.clear();
+                    self.flush_cooked(true);
 
                     b = 0;
                 }
 
                 if is_cc(b, VEOL2) {
                     if echo {
-                        self.output(&[b]);
+                        self.echo(&[b]);
                     }
 
                     self.cooked.push(b);
-                    self.mosi.push_back(self.cooked.clone());
-                    self.cooked.clear();
+                    self.flush_cooked(true);
 
                     b = 0;
                 }
@@ -129,7 +395,7 @@ impl Pty {
                 if is_cc(b, VERASE) {
                     if let Some(_c) = self.cooked.pop() {
                         if echoe {
-                            self.output(&[8, b' ', 8]);
+                            self.echo(&[8, b' ', 8]);
                         }
                     }
 
@@ -202,38 +468,154 @@ impl Pty {
 
             if b != 0 {
                 if echo {
-                    self.output(&[b]);
+                    self.echo(&[b]);
                 }
 
                 // Restart timer after every byte
                 self.timeout_character = Some(self.timeout_count);
 
-                self.cooked.push(b);
+                // A line that no longer fits loses its remaining characters, a
+                // canonical one keeps room for its ending
+                let reserve = if icanon { LINE_END } else { 0 };
+                if self.cooked.len() + reserve < self.mosi.space() {
+                    self.cooked.push(b);
+                }
             }
         }
 
         self.update();
+        self.account();
+        self.wake(Direction::Input);
     }
 
-    pub fn output(&mut self, buf: &[u8]) {
-        //TODO: more output flags
+    /// Input without any processing, the line buffer is skipped when a read
+    /// would take the bytes right away
+    fn input_raw(&mut self, buf: &[u8]) {
+        let cc = self.termios.c_cc;
+
+        if !buf.is_empty() {
+            // Restart timer after every byte
+            self.timeout_character = Some(self.timeout_count);
+        }
+
+        if self.cooked.is_empty() && cc[VMIN] == 1 && cc[VTIME] == 0 {
+            self.mosi.push(buf);
+        } else {
+            let count = buf.len().min(self.mosi.space().saturating_sub(self.cooked.len()));
+            self.cooked.extend_from_slice(&buf[..count]);
+            self.update();
+        }
+
+        self.account();
+        self.wake(Direction::Input);
+    }
+
+    /// True if output has newlines translated
+    fn translates_output(&self) -> bool {
+        let ofl = self.termios.c_oflag;
+
+        ofl & OPOST == OPOST && ofl & ONLCR == ONLCR
+    }
+
+    /// Bytes that `output` queues for `buf` once translated
+    pub fn output_len(&self, buf: &[u8]) -> usize {
+        if self.translates_output() {
+            buf.len() + buf.iter().filter(|&&b| b == b'\n').count()
+        } else {
+            buf.len()
+        }
+    }
+
+    /// True if the control side has output to read, held output does not count
+    pub fn output_ready(&self) -> bool {
+        !self.echo.is_empty() || (!self.miso.is_empty() && self.coalesce_deadline.is_none())
+    }
 
-        let ofl = &self.termios.c_oflag;
+    /// Queue output written by a program, holding it back for a while if the
+    /// coalescing policy asks for it
+    pub fn output_coalesced(&mut self, buf: &[u8]) {
+        self.queue_output(buf);
+
+        if self.coalesce_latency == 0
+            || self.miso.len() >= self.coalesce_batch
+            || self.output_space() == 0
+        {
+            self.release_output();
+        } else if self.coalesce_deadline.is_none() {
+            let latency = (self.coalesce_latency as u64).saturating_mul(1000);
+            let deadline = wait::monotonic().saturating_add(latency);
+            self.coalesce_deadline = Some(deadline);
+            self.wakeups.borrow_mut().add_deadline(deadline, self.key);
+        }
+    }
+
+    /// Let the control side read everything queued so far
+    pub fn release_output(&mut self) {
+        if let Some(deadline) = self.coalesce_deadline.take() {
+            self.wakeups.borrow_mut().remove_deadline(deadline, self.key);
+        }
+        self.wake(Direction::Output);
+    }
 
-        let opost = ofl & OPOST == OPOST;
-        let onlcr = ofl & ONLCR == ONLCR;
+    /// Echo typed input to the control side right away, ahead of queued
+    /// program output if the policy asks for it
+    fn echo(&mut self, buf: &[u8]) {
+        if self.policy & POLICY_ECHO_FIRST == POLICY_ECHO_FIRST {
+            let translate = self.translates_output();
+            let limit = self.output_limit();
+            Self::translate_output(&mut self.echo, buf, translate, limit);
+            self.account();
+            self.wake(Direction::Output);
+        } else {
+            self.queue_output(buf);
+            self.release_output();
+        }
+    }
 
-        let mut vec = Vec::with_capacity(buf.len() + 1);
-        vec.push(0);
+    fn queue_output(&mut self, buf: &[u8]) {
+        let translate = self.translates_output();
+        let limit = self.output_limit();
+        Self::translate_output(&mut self.miso, buf, translate, limit);
+        self.account();
+    }
+
+    /// Bytes output may still queue, echo included, held to the quota
+    fn output_limit(&self) -> usize {
+        self.output_space().min(self.quota.room().unwrap_or(0))
+    }
 
-        for &b in buf.iter() {
-            if opost && onlcr && b == b'\n' {
-                vec.push(b'\r');
+    /// Push `buf` to `ring`, at most `limit` bytes once translated
+    fn translate_output(ring: &mut Ring, buf: &[u8], translate: bool, mut limit: usize) {
+        //TODO: more output flags
+
+        let mut push = |bytes: &[u8]| {
+            limit -= ring.push(&bytes[..bytes.len().min(limit)]);
+        };
+
+        if translate {
+            for line in buf.split_inclusive(|&b| b == b'\n') {
+                if let Some((b'\n', text)) = line.split_last() {
+                    push(text);
+                    push(b"\r\n");
+                } else {
+                    push(line);
+                }
             }
-            vec.push(b);
+        } else {
+            push(buf);
         }
+    }
 
-        self.miso.push_back(vec);
+    /// Move the line buffer to the subterm queue. Reads stop at a `boundary`,
+    /// an empty line buffer always becomes one so the read returns 0
+    fn flush_cooked(&mut self, boundary: bool) {
+        self.mosi.push(&self.cooked);
+        // A full queue loses the boundary like it loses typed bytes
+        if boundary || self.cooked.is_empty() {
+            self.mosi.mark(MOSI_BOUNDARY);
+        }
+        self.cooked.clear();
+        self.wake(Direction::Input);
     }
 
     pub fn update(&mut self) {
@@ -250,14 +632,12 @@ impl Pty {
                 if vmin == 0 {
                     // Polling read, return immediately with data
                     if self.mosi.is_empty() {
-                        self.mosi.push_back(self.cooked.clone());
-                        self.cooked.clear();
+                        self.flush_cooked(false);
                     }
                 } else {
                     // Blocking read, wait until vmin bytes are available
                     if self.cooked.len() >= vmin {
-                        self.mosi.push_back(self.cooked.clone());
-                        self.cooked.clear();
+                        self.flush_cooked(false);
                     }
                 }
             } else {
@@ -265,16 +645,14 @@ impl Pty {
                 if vmin == 0 {
                     // Return when any data is available or the timer expires
                     if !self.cooked.is_empty() {
-                        self.mosi.push_back(self.cooked.clone());
-                        self.cooked.clear();
+                        self.flush_cooked(false);
                     } else {
                         if let Some(timeout_character) = self.timeout_character {
                             if self.timeout_count >= timeout_character.wrapping_add(vtime) {
                                 self.timeout_character = None;
 
                                 if self.mosi.is_empty() {
-                                    self.mosi.push_back(self.cooked.clone());
-                                    self.cooked.clear();
+                                    self.flush_cooked(false);
                                 }
                             }
                         } else {
@@ -286,15 +664,13 @@ impl Pty {
                     // Return when min bytes are received or the timer expires
                     // when any data is available
                     if self.cooked.len() >= vmin {
-                        self.mosi.push_back(self.cooked.clone());
-                        self.cooked.clear();
+                        self.flush_cooked(false);
                     } else if !self.cooked.is_empty() {
                         if let Some(timeout_character) = self.timeout_character {
                             if self.timeout_count >= timeout_character.wrapping_add(vtime) {
                                 self.timeout_character = None;
 
-                                self.mosi.push_back(self.cooked.clone());
-                                self.cooked.clear();
+                                self.flush_cooked(false);
                             }
                         }
                     }
@@ -303,6 +679,107 @@ impl Pty {
         }
     }
 
+    /// Remember a process group that uses the pty, so the secure attention
+    /// key can find it
+    pub fn attach_pgrp(&mut self, pgrp: usize) {
+        self.prune_pgrps();
+        if pgrp != 0 {
+            self.pgrps.insert(pgrp);
+        }
+    }
+
+    /// Forget process groups that no longer exist, before their ids are reused
+    fn prune_pgrps(&mut self) {
+        self.pgrps
+            .retain(|&pgrp| syscall::kill(-(pgrp as isize) as usize, 0).is_ok());
+    }
+
+    /// Returns true once the whole secure attention key sequence was typed
+    fn sak_input(&mut self, b: u8) -> bool {
+        if self.sak.is_empty() {
+            return false;
+        }
+
+        if b == self.sak[self.sak_matched] {
+            self.sak_matched += 1;
+        } else if b == self.sak[0] {
+            self.sak_matched = 1;
+        } else {
+            self.sak_matched = 0;
+        }
+
+        if self.sak_matched == self.sak.len() {
+            self.sak_matched = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Kill everything using the pty and revoke every handle, only a trusted
+    /// login process may open it afterwards
+    pub fn secure_attention(&mut self) {
+        self.audit("sak", format_args!("sid={} pgrp={}", self.sid, self.pgrp));
+
+        self.attach_pgrp(self.sid);
+        self.attach_pgrp(self.pgrp);
+        self.prune_pgrps();
+        for pgrp in std::mem::take(&mut self.pgrps) {
+            let _ = syscall::kill(-(pgrp as isize) as usize, syscall::SIGKILL);
+        }
+
+        self.revoke();
+        self.sak_locked = true;
+    }
+
+    pub fn attach_subterm(&mut self) {
+        self.subterms += 1;
+        self.subterm_hangup = false;
+    }
+
+    pub fn detach_subterm(&mut self) {
+        self.subterms -= 1;
+        if self.subterms == 0 {
+            // Control side reads fail once the remaining output is drained
+            self.subterm_hangup = true;
+            self.release_output();
+        }
+    }
+
+    /// Tell the session and its foreground group that the terminal went away
+    pub fn hangup(&mut self) {
+        for signal in [syscall::SIGHUP, syscall::SIGCONT] {
+            if self.sid != 0 && self.sid != self.pgrp {
+                let _ = syscall::kill(self.sid, signal);
+            }
+
+            if self.pgrp != 0 {
+                let _ = syscall::kill(-(self.pgrp as isize) as usize, signal);
+            }
+        }
+
+        self.sid = 0;
+        self.pgrp = 0;
+    }
+
+    /// Hang up, drop all queued data and have the scheme revoke every handle
+    /// except the control terms
+    pub fn revoke(&mut self) {
+        self.audit("revoke", format_args!("sid={} pgrp={}", self.sid, self.pgrp));
+
+        self.hangup();
+
+        self.cooked.clear();
+        self.miso.clear();
+        self.echo.clear();
+        self.mosi.clear();
+        self.account();
+        self.wake(Direction::Input);
+        self.release_output();
+
+        self.revoke_pending = true;
+    }
+
     pub fn timeout(&mut self, count: u64) {
         if self.timeout_count != count {
             self.timeout_count = count;
@@ -311,3 +788,117 @@ impl Pty {
         }
     }
 }
+
+impl Drop for Pty {
+    fn drop(&mut self) {
+        self.audit(
+            "destroy",
+            format_args!("uid={} gid={}", self.owner_uid, self.owner_gid),
+        );
+
+        // The last control term is gone
+        self.hangup();
+
+        // Blocked subterm requests fail now
+        self.wake(Direction::Input);
+        self.release_output();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use redox_termios::{VERASE, VINTR};
+
+    use super::Pty;
+    use crate::audit::Audit;
+    use crate::limits::{Limits, Quota, Usage};
+    use crate::wait::Wakeups;
+
+    fn pty(pty_bytes: usize) -> Pty {
+        let limits = Limits {
+            pty_bytes,
+            ..Limits::default()
+        };
+        let usage = Rc::new(RefCell::new(Usage::new(limits)));
+        let quota = Quota::new(usage, 1000).unwrap();
+        let audit = Rc::new(RefCell::new(Audit::new()));
+        let wakeups = Rc::new(RefCell::new(Wakeups::new()));
+
+        Pty::new(0, 0, 1000, 1000, quota, audit, wakeups)
+    }
+
+    /// Feed `buf` to the pty the way a control term write does
+    fn write(pty: &mut Pty, buf: &[u8]) -> Option<usize> {
+        let count = pty.input_count(buf).unwrap()?;
+        pty.input(&buf[..count]);
+        Some(count)
+    }
+
+    #[test]
+    fn full_canonical_line_still_ends() {
+        let mut pty = pty(16);
+        // Nobody reads output here, keep echo from filling it
+        pty.termios.c_lflag &= !redox_termios::ECHO;
+
+        for _ in 0..32 {
+            assert_eq!(write(&mut pty, b"a"), Some(1));
+        }
+        assert_eq!(pty.cooked.len(), 14);
+        assert!(pty.input_writable());
+
+        let erase = pty.termios.c_cc[VERASE];
+        assert_eq!(write(&mut pty, &[erase]), Some(1));
+        assert_eq!(pty.cooked.len(), 13);
+
+        assert_eq!(write(&mut pty, b"\n"), Some(1));
+        assert!(pty.cooked.is_empty());
+
+        let mut buf = [0; 16];
+        assert_eq!(pty.mosi.read(&mut buf), 14);
+        assert_eq!(&buf[..14], b"aaaaaaaaaaaaa\n");
+        assert_eq!(pty.mosi.take_mark(), Some(super::MOSI_BOUNDARY));
+    }
+
+    #[test]
+    fn full_queue_waits_for_reader() {
+        let mut pty = pty(16);
+        pty.termios.c_lflag &= !redox_termios::ECHO;
+        // No process group to signal
+        assert_eq!(pty.pgrp, 0);
+
+        assert_eq!(write(&mut pty, b"aaaaaaaaaaaaa\n"), Some(14));
+        // The next line cannot end until the first one is read
+        assert!(!pty.input_writable());
+        assert_eq!(write(&mut pty, b"\n"), None);
+        let intr = pty.termios.c_cc[VINTR];
+        assert_eq!(write(&mut pty, &[intr]), Some(1));
+
+        let mut buf = [0; 16];
+        assert_eq!(pty.mosi.read(&mut buf), 14);
+        assert_eq!(pty.mosi.take_mark(), Some(super::MOSI_BOUNDARY));
+        assert!(pty.input_writable());
+        assert_eq!(write(&mut pty, b"b\n"), Some(2));
+    }
+
+    #[test]
+    fn echo_shares_output_room() {
+        let mut pty = pty(16);
+        pty.policy = crate::policy::POLICY_ECHO_FIRST;
+
+        assert_eq!(write(&mut pty, b"abcdefghij"), Some(10));
+        assert_eq!(pty.echo.len(), 10);
+        assert_eq!(pty.output_space(), 6);
+
+        // Program output only gets what echo left over
+        assert_eq!(pty.output_count(b"0123456789").unwrap(), None);
+        assert_eq!(pty.output_count(b"012345").unwrap(), Some(6));
+
+        // Echo past the limit is lost rather than queued
+        assert_eq!(write(&mut pty, b"klmnopqr"), Some(8));
+        assert_eq!(pty.echo.len(), 16);
+        assert_eq!(pty.output_space(), 0);
+    }
+}
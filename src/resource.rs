@@ -1,24 +1,343 @@
 use std::cell::RefCell;
-use std::rc::Weak;
+use std::mem;
 
-use syscall::error::Result;
-use syscall::flag::EventFlags;
+use redox_scheme::CallerCtx;
+use syscall::error::{Error, Result, EBADF, EINVAL, EIO, EISDIR, EPIPE};
+use syscall::flag::{EventFlags, F_GETFL, F_SETFL, O_ACCMODE, O_RDONLY, O_WRONLY};
 
+use crate::audit::{Audit, PtyAudit};
+use crate::coalesce;
+use crate::controlterm::PtyControlTerm;
+use crate::inject;
+use crate::pgrp;
 use crate::pty::Pty;
+use crate::sak;
+use crate::session;
+use crate::subterm::PtySubTerm;
+use crate::termios;
+use crate::wait::Direction;
+use crate::winsize;
 
-pub trait Resource {
-    fn boxed_clone(&self) -> Box<dyn Resource>;
-    fn pty(&self) -> Weak<RefCell<Pty>>;
-    fn flags(&self) -> usize;
-
-    fn path(&mut self, buf: &mut [u8]) -> Result<usize>;
-    fn read(&mut self, buf: &mut [u8]) -> Result<Option<usize>>;
-    fn write(&mut self, buf: &[u8]) -> Result<Option<usize>>;
-    fn sync(&mut self) -> Result<usize>;
-    fn fcntl(&mut self, cmd: usize, arg: usize) -> Result<usize>;
-    fn fevent(&mut self) -> Result<EventFlags>;
-    fn events(&mut self) -> EventFlags;
-    fn timeout(&self, _count: u64) {
-        // Handled only by PTY control term
+/// What a handle refers to
+#[derive(Clone)]
+pub enum ResourceKind {
+    ControlTerm(PtyControlTerm),
+    SubTerm(PtySubTerm),
+    Audit(PtyAudit),
+    /// Foreground process group
+    Pgrp,
+    Termios,
+    Winsize,
+    /// Pushes bytes into the input stream as if they had been typed
+    Inject,
+    /// Controlling terminal state
+    Session,
+    /// Whether only root may open further subterms (TIOCEXCL/TIOCNXCL)
+    Exclusive,
+    /// Whether subterms may be opened (TIOCSPTLCK/TIOCGPTLCK), control side only
+    Lock,
+    /// Output coalescing policy, control side only
+    Coalesce,
+    /// Policy flags, control side only
+    Policy,
+    /// Revokes the terminal side handles of the pty, the subterms and their files (vhangup)
+    Revoke,
+    /// Secure attention key sequence, control side only
+    Sak,
+    /// Listing of every pty, opened with O_DIRECTORY
+    Dir,
+    /// Listing of the files of one pty, opened with O_DIRECTORY
+    PtyDir,
+    /// Subterm opened with O_STAT, for fstat and fpath without attaching to it
+    Stat,
+    /// Takes the place of a revoked handle, failing everything but close
+    Revoked { notified: bool },
+}
+
+impl ResourceKind {
+    /// Kind of the file `name` of a pty
+    pub fn named(name: &[u8]) -> Option<Self> {
+        Some(match name {
+            b"pgrp" => ResourceKind::Pgrp,
+            b"termios" => ResourceKind::Termios,
+            b"winsize" => ResourceKind::Winsize,
+            b"inject" => ResourceKind::Inject,
+            b"session" => ResourceKind::Session,
+            b"exclusive" => ResourceKind::Exclusive,
+            b"lock" => ResourceKind::Lock,
+            b"coalesce" => ResourceKind::Coalesce,
+            b"policy" => ResourceKind::Policy,
+            b"revoke" => ResourceKind::Revoke,
+            b"sak" => ResourceKind::Sak,
+            _ => return None,
+        })
+    }
+
+    /// True for files only the control side may open
+    pub fn control_only(&self) -> bool {
+        matches!(
+            self,
+            ResourceKind::Lock
+                | ResourceKind::Coalesce
+                | ResourceKind::Policy
+                | ResourceKind::Revoke
+                | ResourceKind::Sak
+        )
+    }
+}
+
+/// An open handle. The header is common to every kind of handle
+#[derive(Clone)]
+pub struct Resource {
+    /// Key of the pty in the pty table, None for handles not tied to a pty
+    pub pty: Option<usize>,
+    pub flags: usize,
+    /// Process, user and group that opened the handle
+    pub pid: usize,
+    pub uid: u32,
+    pub gid: u32,
+    pub kind: ResourceKind,
+}
+
+impl Resource {
+    pub fn new(pty: Option<usize>, flags: usize, ctx: &CallerCtx, kind: ResourceKind) -> Self {
+        Resource {
+            pty,
+            flags,
+            pid: ctx.pid,
+            uid: ctx.uid,
+            gid: ctx.gid,
+            kind,
+        }
+    }
+
+    pub fn is_control(&self) -> bool {
+        matches!(self.kind, ResourceKind::ControlTerm(_))
+    }
+
+    pub fn is_revoked(&self) -> bool {
+        matches!(self.kind, ResourceKind::Revoked { .. })
     }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self.kind, ResourceKind::Dir | ResourceKind::PtyDir)
+    }
+
+    /// True for the terminal ends themselves, not their control files
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self.kind,
+            ResourceKind::ControlTerm(_) | ResourceKind::SubTerm(_)
+        )
+    }
+
+    pub fn path(&self, pty: Option<&Pty>, buf: &mut [u8]) -> Result<usize> {
+        match (&self.kind, pty) {
+            (ResourceKind::Revoked { .. }, _) => Err(Error::new(EIO)),
+            (ResourceKind::Audit(_), _) => Ok(copy_value(b"/scheme/pty/audit", buf)),
+            (ResourceKind::Dir, _) => Ok(copy_value(b"/scheme/pty", buf)),
+            (_, Some(pty)) => pty.path(buf),
+            (_, None) => Err(Error::new(EPIPE)),
+        }
+    }
+
+    pub fn read(
+        &mut self,
+        pty: Option<&mut Pty>,
+        audit: &RefCell<Audit>,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>> {
+        if self.is_revoked() {
+            return Err(Error::new(EIO));
+        }
+        if self.is_dir() {
+            return Err(Error::new(EISDIR));
+        }
+        check_read(self.flags)?;
+
+        let Some(pty) = pty else {
+            return match &mut self.kind {
+                ResourceKind::SubTerm(subterm) => subterm.read(None, self.flags, buf),
+                ResourceKind::Audit(reader) => reader.read(&audit.borrow(), buf).map(Some),
+                ResourceKind::ControlTerm(_) => Err(Error::new(EPIPE)),
+                ResourceKind::Inject | ResourceKind::Revoke => Err(Error::new(EBADF)),
+                // Hung up, reads return 0 from now on
+                _ => Ok(Some(0)),
+            };
+        };
+
+        let i = match &mut self.kind {
+            ResourceKind::ControlTerm(control) => return control.read(pty, self.flags, buf),
+            ResourceKind::SubTerm(subterm) => return subterm.read(Some(pty), self.flags, buf),
+            ResourceKind::Audit(reader) => reader.read(&audit.borrow(), buf)?,
+            ResourceKind::Pgrp => copy_value(&pty.pgrp.to_ne_bytes(), buf),
+            ResourceKind::Termios => copy_value(&pty.termios, buf),
+            ResourceKind::Winsize => copy_value(&pty.winsize, buf),
+            ResourceKind::Session => copy_value(&pty.sid.to_ne_bytes(), buf),
+            ResourceKind::Exclusive => copy_value(&(pty.exclusive as usize).to_ne_bytes(), buf),
+            ResourceKind::Lock => copy_value(&(pty.locked as usize).to_ne_bytes(), buf),
+            ResourceKind::Coalesce => coalesce::read(pty, buf),
+            ResourceKind::Policy => copy_value(&pty.policy.to_ne_bytes(), buf),
+            ResourceKind::Sak => copy_value(&pty.sak, buf),
+            ResourceKind::Inject
+            | ResourceKind::Revoke
+            | ResourceKind::Dir
+            | ResourceKind::PtyDir
+            | ResourceKind::Stat
+            | ResourceKind::Revoked { .. } => {
+                return Err(Error::new(EBADF));
+            }
+        };
+
+        Ok(Some(i))
+    }
+
+    pub fn write(&mut self, pty: Option<&mut Pty>, buf: &[u8]) -> Result<Option<usize>> {
+        if self.is_revoked() {
+            return Err(Error::new(EIO));
+        }
+        if self.is_dir() {
+            return Err(Error::new(EISDIR));
+        }
+        check_write(self.flags)?;
+
+        let Some(pty) = pty else {
+            return match self.kind {
+                ResourceKind::SubTerm(_) => Err(Error::new(EIO)),
+                ResourceKind::Audit(_) => Err(Error::new(EBADF)),
+                _ => Err(Error::new(EPIPE)),
+            };
+        };
+
+        let i = match &mut self.kind {
+            ResourceKind::ControlTerm(control) => return control.write(pty, self.flags, buf),
+            ResourceKind::SubTerm(subterm) => return subterm.write(pty, self.flags, buf),
+            ResourceKind::Inject => return inject::write(pty, self.flags, self.uid, buf),
+            ResourceKind::Pgrp => pgrp::write(pty, self.pid, self.uid, buf)?,
+            ResourceKind::Termios => termios::write(pty, buf),
+            ResourceKind::Winsize => winsize::write(pty, buf),
+            ResourceKind::Session => session::write(pty, self.pid, self.uid, buf)?,
+            ResourceKind::Exclusive => {
+                pty.exclusive = parse_usize(buf)? != 0;
+                buf.len()
+            }
+            ResourceKind::Lock => {
+                pty.locked = parse_usize(buf)? != 0;
+                buf.len()
+            }
+            ResourceKind::Coalesce => coalesce::write(pty, buf)?,
+            ResourceKind::Policy => {
+                pty.policy = parse_usize(buf)?;
+                buf.len()
+            }
+            ResourceKind::Revoke => {
+                pty.revoke();
+                buf.len()
+            }
+            ResourceKind::Sak => sak::write(pty, buf)?,
+            ResourceKind::Audit(_)
+            | ResourceKind::Dir
+            | ResourceKind::PtyDir
+            | ResourceKind::Stat
+            | ResourceKind::Revoked { .. } => {
+                return Err(Error::new(EBADF));
+            }
+        };
+
+        Ok(Some(i))
+    }
+
+    pub fn sync(&mut self, pty: Option<&mut Pty>) -> Result<Option<usize>> {
+        match (&mut self.kind, pty) {
+            (ResourceKind::Revoked { .. }, _) => Err(Error::new(EIO)),
+            (ResourceKind::SubTerm(subterm), pty) => subterm.sync(pty, self.flags),
+            _ => Ok(Some(0)),
+        }
+    }
+
+    pub fn fcntl(&mut self, cmd: usize, arg: usize) -> Result<usize> {
+        if self.is_revoked() {
+            return Err(Error::new(EIO));
+        }
+
+        match cmd {
+            F_GETFL => Ok(self.flags),
+            F_SETFL => {
+                self.flags = (self.flags & O_ACCMODE) | (arg & !O_ACCMODE);
+                Ok(0)
+            }
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    pub fn fevent(&mut self, pty: Option<&Pty>) -> Result<EventFlags> {
+        match &mut self.kind {
+            ResourceKind::ControlTerm(control) => control.notified_reset(),
+            ResourceKind::SubTerm(subterm) => subterm.notified_reset(),
+            ResourceKind::Revoked { .. } => return Err(Error::new(EIO)),
+            _ => return Err(Error::new(EBADF)),
+        }
+
+        Ok(self.events(pty))
+    }
+
+    pub fn events(&mut self, pty: Option<&Pty>) -> EventFlags {
+        match (&mut self.kind, pty) {
+            (ResourceKind::ControlTerm(control), Some(pty)) => control.events(pty),
+            (ResourceKind::SubTerm(subterm), pty) => subterm.events(pty),
+            (ResourceKind::Revoked { notified }, _) => {
+                // Wake up anyone waiting so they see the error
+                if !*notified {
+                    *notified = true;
+                    syscall::EVENT_READ | syscall::EVENT_WRITE
+                } else {
+                    EventFlags::empty()
+                }
+            }
+            _ => EventFlags::empty(),
+        }
+    }
+
+    /// Wait queue of a read, or a write or sync when `write`, that returned None
+    pub fn wait_direction(&self, write: bool) -> Option<Direction> {
+        match self.kind {
+            ResourceKind::ControlTerm(_) if write => Some(Direction::Input),
+            ResourceKind::ControlTerm(_) => Some(Direction::Output),
+            ResourceKind::SubTerm(_) if write => Some(Direction::Output),
+            ResourceKind::SubTerm(_) => Some(Direction::Input),
+            ResourceKind::Inject => Some(Direction::Input),
+            _ => None,
+        }
+    }
+}
+
+/// Fail unless the handle was opened for reading
+pub fn check_read(flags: usize) -> Result<()> {
+    if flags & O_RDONLY == O_RDONLY {
+        Ok(())
+    } else {
+        Err(Error::new(EBADF))
+    }
+}
+
+/// Fail unless the handle was opened for writing
+pub fn check_write(flags: usize) -> Result<()> {
+    if flags & O_WRONLY == O_WRONLY {
+        Ok(())
+    } else {
+        Err(Error::new(EBADF))
+    }
+}
+
+/// Copy as much of `value` into `buf` as fits
+pub fn copy_value(value: &[u8], buf: &mut [u8]) -> usize {
+    let i = value.len().min(buf.len());
+    buf[..i].copy_from_slice(&value[..i]);
+    i
+}
+
+/// Parse a value written as a native endian usize, anything else is EINVAL
+pub fn parse_usize(buf: &[u8]) -> Result<usize> {
+    let bytes = <[u8; mem::size_of::<usize>()]>::try_from(buf).or(Err(Error::new(EINVAL)))?;
+    Ok(usize::from_ne_bytes(bytes))
 }
@@ -0,0 +1,148 @@
+use std::collections::VecDeque;
+
+/// Byte queue with a fixed capacity and marks placed between bytes.
+///
+/// Marks carry a tag and stop reads at their position, so packet boundaries
+/// survive without storing every packet separately. Each mark takes up the
+/// room of one byte, so marks cannot grow the ring past its capacity.
+pub struct Ring {
+    data: VecDeque<u8>,
+    capacity: usize,
+    /// Stream offset of the first byte in `data`
+    offset: u64,
+    /// Stream offsets and tags of marks, in order
+    marks: VecDeque<(u64, u8)>,
+}
+
+impl Ring {
+    pub fn new(capacity: usize) -> Self {
+        Ring {
+            data: VecDeque::new(),
+            capacity,
+            offset: 0,
+            marks: VecDeque::new(),
+        }
+    }
+
+    /// Bytes and marks queued
+    pub fn len(&self) -> usize {
+        self.data.len() + self.marks.len()
+    }
+
+    /// Bytes or marks that can still be pushed
+    pub fn space(&self) -> usize {
+        self.capacity.saturating_sub(self.len())
+    }
+
+    /// True if there are neither bytes nor marks to read
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty() && self.marks.is_empty()
+    }
+
+    /// Append as much of `buf` as fits, returning how much that was
+    pub fn push(&mut self, buf: &[u8]) -> usize {
+        let count = buf.len().min(self.space());
+        self.data.extend(&buf[..count]);
+        count
+    }
+
+    /// Place a mark after the last byte pushed, false if the ring is full
+    pub fn mark(&mut self, tag: u8) -> bool {
+        if self.space() == 0 {
+            return false;
+        }
+
+        let end = self.offset + self.data.len() as u64;
+        self.marks.push_back((end, tag));
+        true
+    }
+
+    /// Remove and return the mark in front of the next byte, if any
+    pub fn take_mark(&mut self) -> Option<u8> {
+        match self.marks.front() {
+            Some(&(offset, tag)) if offset == self.offset => {
+                self.marks.pop_front();
+                Some(tag)
+            }
+            _ => None,
+        }
+    }
+
+    /// Copy bytes into `buf`, stopping at the next mark
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut count = buf.len().min(self.data.len());
+        if let Some(&(offset, _tag)) = self.marks.front() {
+            count = count.min((offset - self.offset) as usize);
+        }
+
+        let (front, back) = self.data.as_slices();
+        if count <= front.len() {
+            buf[..count].copy_from_slice(&front[..count]);
+        } else {
+            buf[..front.len()].copy_from_slice(front);
+            buf[front.len()..count].copy_from_slice(&back[..count - front.len()]);
+        }
+
+        self.data.drain(..count);
+        self.offset += count as u64;
+        count
+    }
+
+    pub fn clear(&mut self) {
+        self.offset += self.data.len() as u64;
+        self.data.clear();
+        self.marks.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ring;
+
+    #[test]
+    fn reads_stop_at_marks() {
+        let mut ring = Ring::new(16);
+        ring.push(b"ab");
+        assert!(ring.mark(1));
+        ring.push(b"cd");
+
+        let mut buf = [0; 8];
+        assert_eq!(ring.take_mark(), None);
+        assert_eq!(ring.read(&mut buf), 2);
+        assert_eq!(&buf[..2], b"ab");
+        assert_eq!(ring.read(&mut buf), 0);
+        assert_eq!(ring.take_mark(), Some(1));
+        assert_eq!(ring.read(&mut buf), 2);
+        assert_eq!(&buf[..2], b"cd");
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn marks_take_up_capacity() {
+        let mut ring = Ring::new(4);
+        assert_eq!(ring.push(b"abc"), 3);
+        assert!(ring.mark(0));
+        assert_eq!(ring.space(), 0);
+        assert!(!ring.mark(0));
+        assert_eq!(ring.push(b"d"), 0);
+
+        let mut buf = [0; 4];
+        assert_eq!(ring.read(&mut buf), 3);
+        assert_eq!(ring.take_mark(), Some(0));
+        assert_eq!(ring.space(), 4);
+    }
+
+    #[test]
+    fn clear_drops_bytes_and_marks() {
+        let mut ring = Ring::new(4);
+        ring.push(b"ab");
+        ring.mark(0);
+        ring.clear();
+
+        assert!(ring.is_empty());
+        assert_eq!(ring.take_mark(), None);
+        ring.push(b"c");
+        let mut buf = [0; 4];
+        assert_eq!(ring.read(&mut buf), 1);
+    }
+}
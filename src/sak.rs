@@ -0,0 +1,18 @@
+use syscall::error::{Error, Result, EINVAL};
+
+use crate::pty::Pty;
+
+/// Longest secure attention key sequence that can be configured
+pub const SAK_MAX: usize = 16;
+
+/// Set the secure attention key sequence of `pty`, empty to disable it
+pub fn write(pty: &mut Pty, buf: &[u8]) -> Result<usize> {
+    if buf.len() > SAK_MAX {
+        return Err(Error::new(EINVAL));
+    }
+
+    pty.sak = buf.to_vec();
+    pty.sak_matched = 0;
+
+    Ok(buf.len())
+}
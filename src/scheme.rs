@@ -1,119 +1,478 @@
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
 use std::rc::Rc;
 use std::str;
 
-use redox_scheme::SchemeBlock;
+use redox_scheme::{CallerCtx, OpenResult, SchemeBlock};
 use syscall::data::Stat;
-use syscall::error::{Error, Result, EBADF, EINVAL, ENOENT};
-use syscall::flag::{EventFlags, MODE_CHR};
+use syscall::dirent::{DirentBuf, DirentKind};
+use syscall::error::{
+    Error, Result, EACCES, EAGAIN, EBADF, EBUSY, EINVAL, EIO, ENOENT, ENOTDIR, EPERM, EPIPE,
+};
+use syscall::flag::{EventFlags, MODE_CHR, MODE_DIR, O_ACCMODE, O_DIRECTORY, O_STAT};
+use syscall::schemev2::NewFdFlags;
 
+use crate::audit::{Audit, PtyAudit};
 use crate::controlterm::PtyControlTerm;
-use crate::pgrp::PtyPgrp;
+use crate::dir;
+use crate::limits::{Limits, Quota, Usage};
 use crate::pty::Pty;
-use crate::resource::Resource;
+use crate::resource::{Resource, ResourceKind};
+use crate::session::{self, O_NOCTTY};
+use crate::slab::Slab;
 use crate::subterm::PtySubTerm;
-use crate::termios::PtyTermios;
-use crate::winsize::PtyWinsize;
+use crate::wait::{WaitKey, Wakeups};
 
 pub struct PtyScheme {
-    next_id: usize,
-    pub handles: BTreeMap<usize, Box<dyn Resource>>,
+    handles: Slab<Resource>,
+    ptys: Slab<Pty>,
+    /// Key of the pty holding each pty number in use
+    numbers: BTreeMap<usize, usize>,
+    /// Handles that need their events checked, besides those of dirty ptys
+    dirty: BTreeSet<usize>,
+    usage: Rc<RefCell<Usage>>,
+    audit: Rc<RefCell<Audit>>,
+    pub wakeups: Rc<RefCell<Wakeups>>,
+    /// Wait queue of the last read or write that returned None
+    pub blocked: Option<WaitKey>,
+}
+
+/// Wait queue for a blocked read or write on `handle`
+fn wait_key(handle: &Resource, write: bool) -> Option<WaitKey> {
+    Some((handle.pty?, handle.wait_direction(write)?))
+}
+
+/// Check that the caller may open the terminal side of `pty`
+fn admit(pty: &mut Pty, flags: usize, ctx: &CallerCtx) -> Result<()> {
+    // The control side has to unlock the pty first, like unlockpt
+    if pty.locked {
+        return Err(Error::new(EIO));
+    }
+
+    if !pty.permitted(ctx.uid, ctx.gid, flags) {
+        return Err(Error::new(EACCES));
+    }
+
+    if pty.exclusive && ctx.uid != 0 {
+        return Err(Error::new(EBUSY));
+    }
+
+    // After the secure attention key only a trusted login process may get back in
+    if pty.sak_locked {
+        if ctx.uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        pty.sak_locked = false;
+    }
+
+    Ok(())
+}
+
+/// Record the caller as a new user of the subterm of `pty`
+fn attach(pty: &mut Pty, flags: usize, ctx: &CallerCtx) {
+    // A session leader without O_NOCTTY picks up a free pty as its controlling terminal
+    if flags & O_NOCTTY == 0 && pty.sid == 0 && session::is_leader(ctx.pid) {
+        pty.sid = ctx.pid;
+        pty.pgrp = ctx.pid;
+        pty.audit("acquire", format_args!("sid={} old_sid=0 uid={}", ctx.pid, ctx.uid));
+    }
+
+    pty.attach_pgrp(syscall::getpgid(ctx.pid).unwrap_or(0));
+    pty.audit(
+        "attach",
+        format_args!("pid={} uid={} gid={}", ctx.pid, ctx.uid, ctx.gid),
+    );
 }
 
 impl PtyScheme {
-    pub fn new() -> Self {
+    pub fn new(limits: Limits) -> Self {
         PtyScheme {
-            next_id: 0,
-            handles: BTreeMap::new(),
+            handles: Slab::new(),
+            ptys: Slab::new(),
+            numbers: BTreeMap::new(),
+            dirty: BTreeSet::new(),
+            usage: Rc::new(RefCell::new(Usage::new(limits))),
+            audit: Rc::new(RefCell::new(Audit::new())),
+            wakeups: Rc::new(RefCell::new(Wakeups::new())),
+            blocked: None,
+        }
+    }
+
+    fn insert_handle(&mut self, handle: Resource) -> Result<usize> {
+        let key = handle.pty;
+        let is_control = handle.is_control();
+        let is_subterm = matches!(handle.kind, ResourceKind::SubTerm(_));
+
+        let id = self.handles.insert(handle)?;
+
+        if let Some(pty) = key.and_then(|key| self.ptys.get_mut(key)) {
+            pty.handles.insert(id);
+            if is_control {
+                pty.controls += 1;
+            } else if is_subterm {
+                pty.attach_subterm();
+            }
+        }
+
+        // New handles start out with their initial events
+        self.dirty.insert(id);
+        Ok(id)
+    }
+
+    /// Lowest pty number not in use, EAGAIN once all of them are
+    fn free_number(&self) -> Result<usize> {
+        let max = self.usage.borrow().limits.pty_number;
+
+        (0..max)
+            .find(|number| !self.numbers.contains_key(number))
+            .ok_or(Error::new(EAGAIN))
+    }
+
+    /// Take the handles whose events may have changed since the last call
+    pub fn dirty_handles(&mut self) -> BTreeSet<usize> {
+        let mut dirty = mem::take(&mut self.dirty);
+
+        for key in self.wakeups.borrow_mut().take_dirty() {
+            if let Some(pty) = self.ptys.get(key) {
+                dirty.extend(&pty.handles);
+            }
+        }
+
+        dirty
+    }
+
+    /// Events of handle `id` not reported yet
+    pub fn events(&mut self, id: usize) -> EventFlags {
+        let Some(handle) = self.handles.get_mut(id) else {
+            return EventFlags::empty();
+        };
+        let pty = handle.pty.and_then(|key| self.ptys.get(key));
+
+        handle.events(pty)
+    }
+
+    /// Advance the termios timeout clock of every pty
+    pub fn timeout(&mut self, count: u64) {
+        for (_key, pty) in self.ptys.iter_mut() {
+            pty.timeout(count);
+        }
+    }
+
+    /// Release the held output of every pty whose coalescing deadline passed
+    pub fn expire(&mut self, now: u64) {
+        let expired = self.wakeups.borrow_mut().expired(now);
+
+        for key in expired {
+            if let Some(pty) = self.ptys.get_mut(key) {
+                pty.release_output();
+            }
+        }
+    }
+
+    /// Replace the handles of a pty that asked for revocation
+    fn revoke_pending(&mut self, key: Option<usize>) {
+        let Some(pty) = key.and_then(|key| self.ptys.get_mut(key)) else {
+            return;
+        };
+        if !mem::take(&mut pty.revoke_pending) {
+            return;
+        }
+
+        for id in pty.handles.clone() {
+            let Some(handle) = self.handles.get_mut(id) else {
+                continue;
+            };
+            // The control side keeps its own files, listings give no access to revoke
+            if handle.is_control()
+                || handle.kind.control_only()
+                || matches!(handle.kind, ResourceKind::PtyDir)
+            {
+                continue;
+            }
+
+            if matches!(handle.kind, ResourceKind::SubTerm(_)) {
+                pty.detach_subterm();
+            }
+            pty.handles.remove(&id);
+
+            handle.pty = None;
+            handle.kind = ResourceKind::Revoked { notified: false };
+            self.dirty.insert(id);
         }
     }
 }
 
 impl SchemeBlock for PtyScheme {
-    fn open(&mut self, path: &str, flags: usize, _uid: u32, _gid: u32) -> Result<Option<usize>> {
+    fn xopen(&mut self, path: &str, flags: usize, ctx: &CallerCtx) -> Result<Option<OpenResult>> {
         let path = path.trim_matches('/');
+        // stat opens and directory opens must neither create a pty nor attach to one
+        let stat = flags & O_STAT == O_STAT;
+        let directory = flags & O_DIRECTORY == O_DIRECTORY;
+
+        let id = if path.is_empty() && (directory || stat) {
+            self.insert_handle(Resource::new(None, flags, ctx, ResourceKind::Dir))?
+        } else if path.is_empty() {
+            let quota = Quota::new(self.usage.clone(), ctx.uid)?;
+            let number = self.free_number()?;
 
-        if path.is_empty() {
-            let id = self.next_id;
-            self.next_id += 1;
+            // Both tables need room before the pty exists, creating it is logged
+            self.handles.vacant_id()?;
+            let key = self.ptys.vacant_id()?;
+            self.ptys.insert(Pty::new(
+                number,
+                key,
+                ctx.uid,
+                ctx.gid,
+                quota,
+                self.audit.clone(),
+                self.wakeups.clone(),
+            ))?;
+            self.numbers.insert(number, key);
 
-            let pty = Rc::new(RefCell::new(Pty::new(id)));
-            self.handles
-                .insert(id, Box::new(PtyControlTerm::new(pty, flags)));
+            let kind = ResourceKind::ControlTerm(PtyControlTerm::new());
+            self.insert_handle(Resource::new(Some(key), flags, ctx, kind))?
+        } else if path == "audit" {
+            if ctx.uid != 0 {
+                return Err(Error::new(EACCES));
+            }
 
-            Ok(Some(id))
+            let kind = ResourceKind::Audit(PtyAudit::new());
+            self.insert_handle(Resource::new(None, flags, ctx, kind))?
         } else {
-            let control_term_id = path.parse::<usize>().or(Err(Error::new(EINVAL)))?;
-            let pty = {
-                let handle = self.handles.get(&control_term_id).ok_or(Error::new(ENOENT))?;
-                handle.pty()
+            let (number, file) = match path.split_once('/') {
+                Some((number, file)) => (number, Some(file)),
+                None => (path, None),
             };
+            let number = number.parse::<usize>().or(Err(Error::new(EINVAL)))?;
+            let key = *self.numbers.get(&number).ok_or(Error::new(ENOENT))?;
+            let pty = self.ptys.get_mut(key).ok_or(Error::new(ENOENT))?;
 
-            let id = self.next_id;
-            self.next_id += 1;
+            // Stat opens are good for fstat and fpath only, reads and writes fail with EBADF
+            let handle_flags = if stat { flags & !O_ACCMODE } else { flags };
+            let kind = match file {
+                // Listing the files of a pty gives no access to it
+                None if directory => ResourceKind::PtyDir,
+                None if stat => ResourceKind::Stat,
+                None => {
+                    admit(pty, flags, ctx)?;
+                    attach(pty, flags, ctx);
+                    ResourceKind::SubTerm(PtySubTerm::new())
+                }
+                Some(_) if directory => return Err(Error::new(ENOTDIR)),
+                Some(file) => {
+                    let kind = match ResourceKind::named(file.as_bytes()) {
+                        Some(kind) if !kind.control_only() => kind,
+                        _ => return Err(Error::new(ENOENT)),
+                    };
+                    if !stat {
+                        admit(pty, flags, ctx)?;
+                    }
+                    kind
+                }
+            };
 
-            self.handles
-                .insert(id, Box::new(PtySubTerm::new(pty, flags)));
+            self.insert_handle(Resource::new(Some(key), handle_flags, ctx, kind))?
+        };
 
-            Ok(Some(id))
-        }
+        Ok(Some(OpenResult::ThisScheme {
+            number: id,
+            flags: NewFdFlags::empty(),
+        }))
     }
 
-    fn dup(&mut self, old_id: usize, buf: &[u8]) -> Result<Option<usize>> {
-        let handle: Box<dyn Resource> = {
-            let old_handle = self.handles.get(&old_id).ok_or(Error::new(EBADF))?;
+    fn xdup(&mut self, old_id: usize, buf: &[u8], ctx: &CallerCtx) -> Result<Option<OpenResult>> {
+        let handle = {
+            let old_handle = self.handles.get(old_id).ok_or(Error::new(EBADF))?;
 
-            if buf.is_empty() {
-                old_handle.boxed_clone()
-            } else if buf == b"pgrp" {
-                Box::new(PtyPgrp::new(old_handle.pty(), old_handle.flags()))
-            } else if buf == b"termios" {
-                Box::new(PtyTermios::new(old_handle.pty(), old_handle.flags()))
-            } else if buf == b"winsize" {
-                Box::new(PtyWinsize::new(old_handle.pty(), old_handle.flags()))
-            } else {
+            if old_handle.is_revoked() {
+                return Err(Error::new(EIO));
+            }
+
+            // Directories only dup into themselves, their files are opened by path
+            if old_handle.is_dir() && !buf.is_empty() {
                 return Err(Error::new(EINVAL));
             }
+
+            // Whoever gets hold of the subterm is killed by the secure attention key, the
+            // files of the pty only change its settings
+            if buf.is_empty() && matches!(old_handle.kind, ResourceKind::SubTerm(_)) {
+                if let Some(pty) = old_handle.pty.and_then(|key| self.ptys.get_mut(key)) {
+                    pty.attach_pgrp(syscall::getpgid(ctx.pid).unwrap_or(0));
+                }
+            }
+
+            let control = old_handle.is_control();
+            let kind = match buf {
+                b"" => None,
+                name => match ResourceKind::named(name) {
+                    Some(kind) if control || !kind.control_only() => Some(kind),
+                    _ => return Err(Error::new(EINVAL)),
+                },
+            };
+
+            match kind {
+                Some(kind) => Resource::new(old_handle.pty, old_handle.flags, ctx, kind),
+                None => old_handle.clone(),
+            }
         };
 
-        let id = self.next_id;
-        self.next_id += 1;
-        self.handles.insert(id, handle);
+        let id = self.insert_handle(handle)?;
 
-        Ok(Some(id))
+        Ok(Some(OpenResult::ThisScheme {
+            number: id,
+            flags: NewFdFlags::empty(),
+        }))
     }
 
     fn read(&mut self, id: usize, buf: &mut [u8], _offset: u64, _fcntl_flags: u32) -> Result<Option<usize>> {
-        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
-        handle.read(buf)
+        let handle = self.handles.get_mut(id).ok_or(Error::new(EBADF))?;
+        let pty = handle.pty.and_then(|key| self.ptys.get_mut(key));
+        let res = handle.read(pty, &self.audit, buf);
+
+        if let Ok(None) = res {
+            self.blocked = wait_key(handle, false);
+        }
+
+        res
     }
 
     fn write(&mut self, id: usize, buf: &[u8], _offset: u64, _fcntl_flags: u32) -> Result<Option<usize>> {
-        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
-        handle.write(buf)
+        let handle = self.handles.get_mut(id).ok_or(Error::new(EBADF))?;
+        let pty = handle.pty.and_then(|key| self.ptys.get_mut(key));
+        let res = handle.write(pty, buf);
+
+        if let Ok(None) = res {
+            self.blocked = wait_key(handle, true);
+        }
+
+        let key = handle.pty;
+        self.revoke_pending(key);
+
+        res
     }
 
     fn fcntl(&mut self, id: usize, cmd: usize, arg: usize) -> Result<Option<usize>> {
-        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let handle = self.handles.get_mut(id).ok_or(Error::new(EBADF))?;
         handle.fcntl(cmd, arg).map(Some)
     }
 
     fn fevent(&mut self, id: usize, _flags: EventFlags) -> Result<Option<EventFlags>> {
-        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
-        handle.fevent().map(Some)
+        let handle = self.handles.get_mut(id).ok_or(Error::new(EBADF))?;
+        let pty = handle.pty.and_then(|key| self.ptys.get(key));
+        handle.fevent(pty).map(Some)
     }
 
     fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<Option<usize>> {
-        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
-        handle.path(buf).map(Some)
+        let handle = self.handles.get(id).ok_or(Error::new(EBADF))?;
+        let pty = handle.pty.and_then(|key| self.ptys.get(key));
+        handle.path(pty, buf).map(Some)
+    }
+
+    fn fchmod(&mut self, id: usize, mode: u16) -> Result<Option<usize>> {
+        let handle = self.handles.get(id).ok_or(Error::new(EBADF))?;
+        let pty = handle
+            .pty
+            .and_then(|key| self.ptys.get_mut(key))
+            .ok_or(Error::new(EIO))?;
+        if !handle.is_terminal() {
+            return Err(Error::new(EPERM));
+        }
+        let uid = handle.uid;
+
+        if uid != 0 && uid != pty.owner_uid {
+            return Err(Error::new(EPERM));
+        }
+
+        pty.mode = mode & 0o777;
+        pty.audit("chmod", format_args!("mode={:o} uid={}", pty.mode, uid));
+
+        Ok(Some(0))
+    }
+
+    /// Change the owner and group of the pty. Permissions are checked against
+    /// whoever opened the handle, the caller of fchown is not known here
+    fn fchown(&mut self, id: usize, new_uid: u32, new_gid: u32) -> Result<Option<usize>> {
+        let handle = self.handles.get(id).ok_or(Error::new(EBADF))?;
+        let pty = handle
+            .pty
+            .and_then(|key| self.ptys.get_mut(key))
+            .ok_or(Error::new(EIO))?;
+        if !handle.is_terminal() {
+            return Err(Error::new(EPERM));
+        }
+        let uid = handle.uid;
+
+        // -1 leaves the value alone, only root may give the pty away or hand it
+        // to a group other than the opener's own
+        if uid != 0
+            && (uid != pty.owner_uid
+                || (new_uid != u32::MAX && new_uid != uid)
+                || (new_gid != u32::MAX && new_gid != handle.gid))
+        {
+            return Err(Error::new(EPERM));
+        }
+
+        if new_uid != u32::MAX {
+            pty.owner_uid = new_uid;
+        }
+        if new_gid != u32::MAX {
+            pty.owner_gid = new_gid;
+        }
+        pty.audit(
+            "chown",
+            format_args!("owner={} group={} uid={}", pty.owner_uid, pty.owner_gid, uid),
+        );
+
+        Ok(Some(0))
+    }
+
+    fn getdents<'buf>(
+        &mut self,
+        id: usize,
+        buf: DirentBuf<&'buf mut [u8]>,
+        opaque_offset: u64,
+    ) -> Result<Option<DirentBuf<&'buf mut [u8]>>> {
+        let handle = self.handles.get(id).ok_or(Error::new(EBADF))?;
+        let offset = usize::try_from(opaque_offset).or(Err(Error::new(EINVAL)))?;
+
+        match handle.kind {
+            // Resume after the last pty number listed, ptys may come and go in between
+            ResourceKind::Dir => {
+                let entries = self.numbers.range(offset..).map(|(&number, _)| {
+                    (number as u64 + 1, number.to_string(), DirentKind::Directory)
+                });
+                dir::list(buf, entries).map(Some)
+            }
+            ResourceKind::PtyDir => {
+                if handle.pty.and_then(|key| self.ptys.get(key)).is_none() {
+                    return Err(Error::new(EPIPE));
+                }
+
+                let entries = dir::PTY_FILES.iter().enumerate().skip(offset).map(|(i, name)| {
+                    (i as u64 + 1, name.to_string(), DirentKind::CharDev)
+                });
+                dir::list(buf, entries).map(Some)
+            }
+            ResourceKind::Revoked { .. } => Err(Error::new(EIO)),
+            _ => Err(Error::new(ENOTDIR)),
+        }
     }
 
     fn fstat(&mut self, id: usize, stat: &mut Stat) -> Result<Option<usize>> {
-        let _handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let handle = self.handles.get(id).ok_or(Error::new(EBADF))?;
+        let pty = handle.pty.and_then(|key| self.ptys.get(key));
+
+        let mode = if handle.is_dir() {
+            MODE_DIR | 0o755
+        } else {
+            MODE_CHR | pty.map_or(0o666, |pty| pty.mode)
+        };
 
         *stat = Stat {
-            st_mode: MODE_CHR | 0o666,
+            st_mode: mode,
+            st_uid: pty.map_or(0, |pty| pty.owner_uid),
+            st_gid: pty.map_or(0, |pty| pty.owner_gid),
             ..Default::default()
         };
 
@@ -121,12 +480,45 @@ impl SchemeBlock for PtyScheme {
     }
 
     fn fsync(&mut self, id: usize) -> Result<Option<usize>> {
-        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
-        handle.sync().map(Some)
+        let handle = self.handles.get_mut(id).ok_or(Error::new(EBADF))?;
+        let pty = handle.pty.and_then(|key| self.ptys.get_mut(key));
+        let res = handle.sync(pty);
+
+        // A sync waits for room in the queue like a write
+        if let Ok(None) = res {
+            self.blocked = wait_key(handle, true);
+        }
+
+        res
     }
 
     fn close(&mut self, id: usize) -> Result<Option<usize>> {
-        drop(self.handles.remove(&id));
+        let Some(handle) = self.handles.remove(id) else {
+            return Ok(Some(0));
+        };
+        let Some(key) = handle.pty else {
+            return Ok(Some(0));
+        };
+        let Some(pty) = self.ptys.get_mut(key) else {
+            return Ok(Some(0));
+        };
+
+        pty.handles.remove(&id);
+
+        match handle.kind {
+            ResourceKind::ControlTerm(_) => {
+                pty.controls -= 1;
+                if pty.controls == 0 {
+                    // The last control term is gone, so is the pty
+                    if let Some(pty) = self.ptys.remove(key) {
+                        self.numbers.remove(&pty.number);
+                        self.dirty.extend(&pty.handles);
+                    }
+                }
+            }
+            ResourceKind::SubTerm(_) => pty.detach_subterm(),
+            _ => (),
+        }
 
         Ok(Some(0))
     }
@@ -0,0 +1,71 @@
+use syscall::error::{Error, Result, EINVAL, EPERM};
+
+use crate::pty::Pty;
+use crate::resource::parse_usize;
+
+/// Do not make the pty the controlling terminal when opening it.
+///
+/// Not exported by redox_syscall yet, matches the value used by relibc.
+pub const O_NOCTTY: usize = 0x0000_0200;
+
+/// Give up the pty as controlling terminal (TIOCNOTTY)
+pub const SESSION_RELEASE: usize = 0;
+/// Make the pty the controlling terminal of the caller's session (TIOCSCTTY)
+pub const SESSION_ACQUIRE: usize = 1;
+/// Take the pty away from another session, root only (TIOCSCTTY with arg 1)
+pub const SESSION_STEAL: usize = 2;
+
+/// Returns true if `pid` leads its own session.
+///
+/// Redox has no sessions of its own yet and relibc implements `setsid` as
+/// `setpgid(0, 0)`, so process group leaders stand in for session leaders.
+pub fn is_leader(pid: usize) -> bool {
+    syscall::getpgid(pid) == Ok(pid)
+}
+
+/// Returns true if `pid` may make `pgrp` the foreground group of `pty`.
+///
+/// The session of an arbitrary process cannot be queried, so only groups known
+/// to be on this terminal qualify: the session leader's own group, groups
+/// already attached to the pty and the caller's own group.
+pub fn in_session(pty: &Pty, pid: usize, pgrp: usize) -> bool {
+    (pty.sid != 0 && pgrp == pty.sid)
+        || pty.pgrps.contains(&pgrp)
+        || syscall::getpgid(pid) == Ok(pgrp)
+}
+
+/// Run a session command for `pid` on `pty`
+pub fn write(pty: &mut Pty, pid: usize, uid: u32, buf: &[u8]) -> Result<usize> {
+    match parse_usize(buf)? {
+        SESSION_RELEASE => {
+            // Only the session leader detaches the terminal from the session
+            if pty.sid == pid {
+                pty.sid = 0;
+                pty.pgrp = 0;
+                pty.audit("release", format_args!("sid={} uid={}", pid, uid));
+            }
+        }
+        command @ (SESSION_ACQUIRE | SESSION_STEAL) => {
+            if !is_leader(pid) {
+                return Err(Error::new(EPERM));
+            }
+
+            let stealing = command == SESSION_STEAL && uid == 0;
+            if pty.sid != 0 && pty.sid != pid && !stealing {
+                return Err(Error::new(EPERM));
+            }
+
+            pty.audit(
+                "acquire",
+                format_args!("sid={} old_sid={} uid={}", pid, pty.sid, uid),
+            );
+
+            pty.sid = pid;
+            pty.pgrp = pid;
+            pty.attach_pgrp(pid);
+        }
+        _ => return Err(Error::new(EINVAL)),
+    }
+
+    Ok(buf.len())
+}
@@ -0,0 +1,132 @@
+use syscall::error::{Error, Result, EMFILE};
+
+/// Bits of an id that index the table, the rest hold the generation
+const INDEX_BITS: u32 = usize::BITS / 2;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+
+struct Slot<T> {
+    generation: usize,
+    value: Option<T>,
+}
+
+/// Table with O(1) lookups. Ids carry the generation of their slot, so an id
+/// stays invalid once its entry is removed, even after the slot is reused
+pub struct Slab<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<usize>,
+}
+
+impl<T> Slab<T> {
+    pub fn new() -> Self {
+        Slab {
+            slots: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    fn id(&self, index: usize) -> usize {
+        (self.slots[index].generation << INDEX_BITS) | index
+    }
+
+    fn slot(&self, id: usize) -> Option<&Slot<T>> {
+        let slot = self.slots.get(id & INDEX_MASK)?;
+        (slot.generation == id >> INDEX_BITS && slot.value.is_some()).then_some(slot)
+    }
+
+    /// Id the next `insert` returns, EMFILE once every index is taken
+    pub fn vacant_id(&self) -> Result<usize> {
+        match self.free.last() {
+            Some(&index) => Ok(self.id(index)),
+            // Past this the index would spill into the generation
+            None if self.slots.len() > INDEX_MASK => Err(Error::new(EMFILE)),
+            None => Ok(self.slots.len()),
+        }
+    }
+
+    pub fn insert(&mut self, value: T) -> Result<usize> {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None if self.slots.len() > INDEX_MASK => return Err(Error::new(EMFILE)),
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: None,
+                });
+                self.slots.len() - 1
+            }
+        };
+
+        self.slots[index].value = Some(value);
+        Ok(self.id(index))
+    }
+
+    pub fn get(&self, id: usize) -> Option<&T> {
+        self.slot(id)?.value.as_ref()
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut T> {
+        self.slot(id)?;
+        self.slots[id & INDEX_MASK].value.as_mut()
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<T> {
+        self.slot(id)?;
+
+        let index = id & INDEX_MASK;
+        let slot = &mut self.slots[index];
+        slot.generation = (slot.generation + 1) & (usize::MAX >> INDEX_BITS);
+        self.free.push(index);
+        slot.value.take()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (usize, &mut T)> {
+        self.slots.iter_mut().enumerate().filter_map(|(index, slot)| {
+            let id = (slot.generation << INDEX_BITS) | index;
+            Some((id, slot.value.as_mut()?))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Slab;
+
+    #[test]
+    fn vacant_id_predicts_insert() {
+        let mut slab = Slab::new();
+        let id = slab.vacant_id().unwrap();
+        assert_eq!(slab.insert('a').unwrap(), id);
+
+        slab.remove(id);
+        let id = slab.vacant_id().unwrap();
+        assert_eq!(slab.insert('b').unwrap(), id);
+    }
+
+    #[test]
+    fn stale_ids_are_rejected() {
+        let mut slab = Slab::new();
+        let old = slab.insert('a').unwrap();
+        assert_eq!(slab.remove(old), Some('a'));
+
+        // The slot is reused under a new generation
+        let new = slab.insert('b').unwrap();
+        assert_ne!(old, new);
+        assert_eq!(slab.get(old), None);
+        assert_eq!(slab.get_mut(old), None);
+        assert_eq!(slab.remove(old), None);
+        assert_eq!(slab.get(new), Some(&'b'));
+    }
+
+    #[test]
+    fn removed_ids_are_gone() {
+        let mut slab = Slab::new();
+        let a = slab.insert('a').unwrap();
+        let b = slab.insert('b').unwrap();
+        slab.remove(a);
+
+        assert_eq!(slab.get(a), None);
+        assert_eq!(slab.remove(a), None);
+        let items: Vec<_> = slab.iter_mut().map(|(id, value)| (id, *value)).collect();
+        assert_eq!(items, [(b, 'b')]);
+    }
+}
@@ -1,76 +1,45 @@
-use std::cell::RefCell;
-use std::rc::Weak;
+use syscall::error::{Error, Result, EAGAIN, EIO};
+use syscall::flag::{EventFlags, O_NONBLOCK};
 
-use syscall::error::{Error, Result, EAGAIN, EINVAL, EPIPE};
-use syscall::flag::{EventFlags, F_GETFL, F_SETFL, O_ACCMODE, O_NONBLOCK};
-
-use crate::pty::Pty;
-use crate::resource::Resource;
+use crate::pty::{Pty, MISO_SYNC};
+use crate::wait::Direction;
 
 /// Read side of a pipe
 #[derive(Clone)]
 pub struct PtySubTerm {
-    pty: Weak<RefCell<Pty>>,
-    flags: usize,
     notified_read: bool,
     notified_write: bool,
 }
 
 impl PtySubTerm {
-    pub fn new(pty: Weak<RefCell<Pty>>, flags: usize) -> Self {
+    pub fn new() -> Self {
         PtySubTerm {
-            pty: pty,
-            flags: flags,
             notified_read: false,
             notified_write: false,
         }
     }
-}
-
-impl Resource for PtySubTerm {
-    fn boxed_clone(&self) -> Box<dyn Resource> {
-        Box::new(self.clone())
-    }
-
-    fn pty(&self) -> Weak<RefCell<Pty>> {
-        self.pty.clone()
-    }
-
-    fn flags(&self) -> usize {
-        self.flags
-    }
 
-    fn path(&mut self, buf: &mut [u8]) -> Result<usize> {
-        if let Some(pty_lock) = self.pty.upgrade() {
-            pty_lock.borrow_mut().path(buf)
-        } else {
-            Err(Error::new(EPIPE))
-        }
-    }
-
-    fn read(&mut self, buf: &mut [u8]) -> Result<Option<usize>> {
-        
+    pub fn read(&mut self, pty: Option<&mut Pty>, flags: usize, buf: &mut [u8]) -> Result<Option<usize>> {
         self.notified_read = false;
 
-        if let Some(pty_lock) = self.pty.upgrade() {
-            let mut pty = pty_lock.borrow_mut();
-
+        if let Some(pty) = pty {
             pty.update();
 
-            if let Some(packet) = pty.mosi.pop_front() {
-                let mut i = 0;
+            if !pty.mosi.is_empty() {
+                // Reads end at a boundary, one right in front reads as EOF
+                let i = if pty.mosi.take_mark().is_some() {
+                    0
+                } else {
+                    let i = pty.mosi.read(buf);
+                    pty.mosi.take_mark();
+                    i
+                };
 
-                while i < buf.len() && i < packet.len() {
-                    buf[i] = packet[i];
-                    i += 1;
-                }
-
-                if i < packet.len() {
-                    pty.mosi.push_front(packet[i..].to_vec());
-                }
+                pty.account();
+                pty.wake(Direction::Input);
 
                 Ok(Some(i))
-            } else if self.flags & O_NONBLOCK == O_NONBLOCK {
+            } else if flags & O_NONBLOCK == O_NONBLOCK {
                 Err(Error::new(EAGAIN))
             } else {
                 Ok(None)
@@ -80,57 +49,50 @@ impl Resource for PtySubTerm {
         }
     }
 
-    fn write(&mut self, buf: &[u8]) -> Result<Option<usize>> {
-        if let Some(pty_lock) = self.pty.upgrade() {
-            let mut pty = pty_lock.borrow_mut();
-
-            if pty.miso.len() >= 64 {
-                return Ok(None);
-            }
+    pub fn write(&mut self, pty: &mut Pty, flags: usize, buf: &[u8]) -> Result<Option<usize>> {
+        let Some(count) = pty.output_count(buf)? else {
+            return if flags & O_NONBLOCK == O_NONBLOCK {
+                Err(Error::new(EAGAIN))
+            } else {
+                Ok(None)
+            };
+        };
 
-            pty.output(buf);
+        pty.output_coalesced(&buf[..count]);
 
-            Ok(Some(buf.len()))
-        } else {
-            Err(Error::new(EPIPE))
-        }
+        Ok(Some(count))
     }
 
-    fn sync(&mut self) -> Result<usize> {
-        if let Some(pty_lock) = self.pty.upgrade() {
-            let mut pty = pty_lock.borrow_mut();
-
-            pty.miso.push_back(vec![1]);
+    pub fn sync(&mut self, pty: Option<&mut Pty>, flags: usize) -> Result<Option<usize>> {
+        if let Some(pty) = pty {
+            // The control side has to catch up before another sync fits
+            if pty.output_space() == 0 || !pty.miso.mark(MISO_SYNC) {
+                return if flags & O_NONBLOCK == O_NONBLOCK {
+                    Err(Error::new(EAGAIN))
+                } else {
+                    Ok(None)
+                };
+            }
+            pty.account();
+            pty.release_output();
 
-            Ok(0)
+            Ok(Some(0))
         } else {
-            Err(Error::new(EPIPE))
+            Err(Error::new(EIO))
         }
     }
 
-    fn fcntl(&mut self, cmd: usize, arg: usize) -> Result<usize> {
-        match cmd {
-            F_GETFL => Ok(self.flags),
-            F_SETFL => {
-                self.flags = (self.flags & O_ACCMODE) | (arg & !O_ACCMODE);
-                Ok(0)
-            }
-            _ => Err(Error::new(EINVAL)),
-        }
-    }
-
-    fn fevent(&mut self) -> Result<EventFlags> {
-        self.notified_read = false; // resend
+    /// Resend the current events, for fevent
+    pub fn notified_reset(&mut self) {
+        self.notified_read = false;
         self.notified_write = false;
-        Ok(self.events())
     }
 
-    fn events(&mut self) -> EventFlags {
+    pub fn events(&mut self, pty: Option<&Pty>) -> EventFlags {
         let mut events = EventFlags::empty();
 
-        if let Some(pty_lock) = self.pty.upgrade() {
-            let pty = pty_lock.borrow();
-            if pty.mosi.front().is_some() {
+        if let Some(pty) = pty {
+            if !pty.mosi.is_empty() {
                 if !self.notified_read {
                     self.notified_read = true;
                     events |= syscall::EVENT_READ;
@@ -138,11 +100,26 @@ impl Resource for PtySubTerm {
             } else {
                 self.notified_read = false;
             }
-        }
 
-        if !self.notified_write {
-            self.notified_write = true;
-            events |= syscall::EVENT_WRITE;
+            // Edge triggered, a full queue re-arms it
+            if pty.output_writable() {
+                if !self.notified_write {
+                    self.notified_write = true;
+                    events |= syscall::EVENT_WRITE;
+                }
+            } else {
+                self.notified_write = false;
+            }
+        } else {
+            // Hung up, reads return 0 and writes fail from now on
+            if !self.notified_read {
+                self.notified_read = true;
+                events |= syscall::EVENT_READ;
+            }
+            if !self.notified_write {
+                self.notified_write = true;
+                events |= syscall::EVENT_WRITE;
+            }
         }
 
         events
@@ -0,0 +1,89 @@
+use std::collections::BTreeSet;
+
+use syscall::data::TimeSpec;
+use syscall::flag::CLOCK_MONOTONIC;
+
+/// Direction data flows through a pty, each with its own wait queue
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Direction {
+    /// Control term to subterm, through `cooked` and `mosi`
+    Input,
+    /// Subterm to control term, through `miso`
+    Output,
+}
+
+/// Pty key and direction of a wait queue
+pub type WaitKey = (usize, Direction);
+
+/// Wait queues whose condition changed since the event loop last looked,
+/// shared by every pty
+pub struct Wakeups {
+    woken: BTreeSet<WaitKey>,
+    /// Ptys whose handles may have new events to post
+    dirty: BTreeSet<usize>,
+    /// Monotonic deadlines in nanoseconds and the ptys holding output until then
+    deadlines: BTreeSet<(u64, usize)>,
+}
+
+impl Wakeups {
+    pub fn new() -> Self {
+        Wakeups {
+            woken: BTreeSet::new(),
+            dirty: BTreeSet::new(),
+            deadlines: BTreeSet::new(),
+        }
+    }
+
+    pub fn wake(&mut self, key: WaitKey) {
+        self.woken.insert(key);
+        self.dirty.insert(key.0);
+    }
+
+    /// Take every queue woken so far
+    pub fn take(&mut self) -> BTreeSet<WaitKey> {
+        std::mem::take(&mut self.woken)
+    }
+
+    /// Take every pty marked dirty so far
+    pub fn take_dirty(&mut self) -> BTreeSet<usize> {
+        std::mem::take(&mut self.dirty)
+    }
+
+    /// Expire the pty with key `key` at `deadline`
+    pub fn add_deadline(&mut self, deadline: u64, key: usize) {
+        self.deadlines.insert((deadline, key));
+    }
+
+    pub fn remove_deadline(&mut self, deadline: u64, key: usize) {
+        self.deadlines.remove(&(deadline, key));
+    }
+
+    /// Earliest deadline still to come
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.deadlines.first().map(|&(deadline, _key)| deadline)
+    }
+
+    /// Take the ptys whose deadline passed by `now`
+    pub fn expired(&mut self, now: u64) -> Vec<usize> {
+        let mut keys = Vec::new();
+        while let Some(&(deadline, key)) = self.deadlines.first() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.pop_first();
+            keys.push(key);
+        }
+        keys
+    }
+}
+
+/// Monotonic clock in nanoseconds, the one the event loop timer runs on
+pub fn monotonic() -> u64 {
+    let mut time = TimeSpec::default();
+    let _ = syscall::clock_gettime(CLOCK_MONOTONIC, &mut time);
+    nanoseconds(&time)
+}
+
+pub fn nanoseconds(time: &TimeSpec) -> u64 {
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}